@@ -0,0 +1,147 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kademlia_dht::{Key, NodeConfig, NodeData, RoutingTable, SystemClock};
+use rand::{Rng, SeedableRng, StdRng};
+use std::cmp;
+use std::sync::Arc;
+
+/// Constructs a routing table with 256 k-buckets by inserting nodes at every distance from the
+/// node's id, which is zero.
+fn routing_table(rng: &mut StdRng) -> RoutingTable {
+    let node_data = NodeData {
+        addr: "127.0.0.1:8080".to_string(),
+        id: Key([0; 32]),
+    };
+    let config = NodeConfig::default();
+    let replication_param = config.replication_param;
+    let mut routing_table =
+        RoutingTable::new(Arc::new(node_data), Arc::new(config), Arc::new(SystemClock));
+    for index in 0..256 {
+        for port in 0..replication_param {
+            let mut id = [0; 32];
+            rng.fill_bytes(&mut id);
+            for byte in id.iter_mut().take(index / 8) {
+                *byte = 0;
+            }
+            id[index / 8] &= 0xFF >> (index % 8);
+            id[index / 8] |= 0x80 >> (index % 8);
+            routing_table.update_node(NodeData {
+                addr: format!("127.0.0.{}:{}", index, 8081 + port),
+                id: Key(id),
+            });
+        }
+    }
+    assert_eq!(routing_table.prefixes().len(), 256);
+    routing_table
+}
+
+fn xor(a: &Key, b: &Key) -> Key {
+    let mut ret = [0; 32];
+    for (i, byte) in ret.iter_mut().enumerate() {
+        *byte = a.0[i] ^ b.0[i];
+    }
+    Key(ret)
+}
+
+fn leading_zeros(key: &Key) -> usize {
+    match key.0.iter().position(|byte| *byte != 0) {
+        Some(i) => i * 8 + key.0[i].leading_zeros() as usize,
+        None => 256,
+    }
+}
+
+/// Returns the nodes of `routing_table` in the layout of the original routing table: a list of 256
+/// k-buckets where bucket `i` holds the nodes whose distance to the node's id has `i` leading
+/// zeros.
+fn buckets(routing_table: &RoutingTable, node_id: &Key) -> Vec<Vec<NodeData>> {
+    let mut buckets = vec![Vec::new(); 256];
+    for node in routing_table.nodes() {
+        let index = leading_zeros(&xor(&node.id, node_id)).min(255);
+        buckets[index].push(node);
+    }
+    buckets
+}
+
+/// The original implementation of `RoutingTable::get_closest_nodes`, which concatenated the bucket
+/// that `key` would reside in with every bucket after it, and the buckets before it until there
+/// were `count` nodes, and then sorted all of them.
+fn get_closest_nodes_by_sorting(
+    buckets: &[Vec<NodeData>],
+    node_id: &Key,
+    key: &Key,
+    count: usize,
+) -> Vec<NodeData> {
+    let index = cmp::min(leading_zeros(&xor(node_id, key)), buckets.len() - 1);
+    let mut ret = Vec::new();
+
+    // the closest keys are guaranteed to be in bucket which the key would reside
+    ret.extend_from_slice(&buckets[index]);
+
+    if ret.len() < count {
+        // the distance between target key and keys is not necessarily monotonic
+        // in range (key.leading_zeros(), self.buckets.len()], so we must iterate
+        for bucket in &buckets[index + 1..] {
+            ret.extend_from_slice(bucket);
+        }
+    }
+
+    if ret.len() < count {
+        // the distance between target key and keys in [0, key.leading_zeros())
+        // is monotonicly decreasing by bucket
+        for bucket in buckets[..index].iter().rev() {
+            ret.extend_from_slice(bucket);
+            if ret.len() >= count {
+                break;
+            }
+        }
+    }
+
+    ret.sort_by_key(|node| xor(&node.id, key));
+    ret.truncate(count);
+    ret
+}
+
+fn bench_get_closest_nodes(c: &mut Criterion) {
+    let mut rng = StdRng::from_seed(&[0][..]);
+    let routing_table = routing_table(&mut rng);
+    let node_id = Key([0; 32]);
+    let buckets = buckets(&routing_table, &node_id);
+    let keys: Vec<Key> = (0..64)
+        .map(|_| {
+            let mut key = [0; 32];
+            rng.fill_bytes(&mut key);
+            Key(key)
+        })
+        .collect();
+
+    for key in &keys {
+        assert_eq!(
+            routing_table.get_closest_nodes(key, 20),
+            get_closest_nodes_by_sorting(&buckets, &node_id, key, 20),
+        );
+    }
+
+    let mut group = c.benchmark_group("get_closest_nodes");
+    group.bench_function("sort_per_bucket", |b| {
+        b.iter(|| {
+            for key in &keys {
+                black_box(routing_table.get_closest_nodes(black_box(key), 20));
+            }
+        })
+    });
+    group.bench_function("sort_all", |b| {
+        b.iter(|| {
+            for key in &keys {
+                black_box(get_closest_nodes_by_sorting(
+                    &buckets,
+                    &node_id,
+                    black_box(key),
+                    20,
+                ));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_get_closest_nodes);
+criterion_main!(benches);
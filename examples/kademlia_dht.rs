@@ -1,7 +1,5 @@
 use kademlia_dht::{Key, Node};
 use sha3::{Digest, Sha3_256};
-use std::thread;
-use std::time::Duration;
 
 fn clone_into_array<A, T>(slice: &[T]) -> A
 where
@@ -19,16 +17,14 @@ fn get_key(key: &str) -> Key {
     Key(clone_into_array(hasher.result().as_slice()))
 }
 
-fn main() {
-    let mut node = Node::new("localhost", "8080", None);
+fn main() -> Result<(), kademlia_dht::Error> {
+    let mut node = Node::new("localhost", "8080", None)?;
 
     let key = get_key("Hello");
     let value = "World";
 
-    node.insert(key, value);
+    node.insert_str(key, value)?;
 
-    // inserting is asynchronous, so sleep for a second
-    thread::sleep(Duration::from_millis(1000));
-
-    assert_eq!(node.get(&key).unwrap(), value);
+    assert_eq!(node.get_str(&key)?.unwrap(), value);
+    Ok(())
 }
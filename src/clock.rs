@@ -0,0 +1,21 @@
+use time::SteadyTime;
+
+/// A source of the current time.
+///
+/// `MemoryStorage`, `RoutingTable`, and the blacklist and reassembly of received datagrams read the
+/// time through a `Clock` so that the simulator and tests can replace the system clock with a
+/// virtual one.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SteadyTime;
+}
+
+/// A `Clock` that returns the system's monotonic time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SteadyTime {
+        SteadyTime::now()
+    }
+}
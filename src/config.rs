@@ -0,0 +1,326 @@
+use crate::key::Key;
+use crate::node::node_data::NodeData;
+use crate::{
+    BLACKLIST_DURATION, BOOTSTRAP_RETRY_INTERVAL, BUCKET_REFRESH_INTERVAL, CONCURRENCY_PARAM,
+    KEY_EXPIRATION, MAX_BOOTSTRAP_RETRY_INTERVAL, MAX_FAILURES, MAX_TTL, MAX_VALUE_SIZE,
+    MESSAGE_LENGTH, QUESTIONABLE_INTERVAL, REPLICATION_INTERVAL, REPLICATION_PARAM,
+    REPUBLISH_INTERVAL, REQUEST_TIMEOUT,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The policy that decides which key-value pairs are evicted when storing a new pair would exceed
+/// the storage quota of a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the pairs whose keys are furthest from the node's id. If the new pair is itself the
+    /// furthest, it is rejected instead.
+    FurthestFirst,
+    /// Evicts the pairs that were stored the longest time ago.
+    OldestFirst,
+    /// Never evicts pairs, and rejects the new pair instead.
+    Reject,
+}
+
+/// The protocol parameters of a single node.
+///
+/// Every node carries its own `NodeConfig` so that nodes with different parameters can coexist in
+/// the same process. The default configuration uses the values recommended by the Kademlia paper.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeConfig {
+    /// The id of the node. If `None`, the id is read from `identity_file`.
+    pub id: Option<Key>,
+    /// The file that holds the id of the node. If the file does not exist, a random id is
+    /// generated and written to it. If both `id` and `identity_file` are `None`, the node has a
+    /// random id.
+    pub identity_file: Option<PathBuf>,
+    /// The nodes to bootstrap from in addition to the one given when the node is constructed.
+    pub bootstrap_nodes: Vec<NodeData>,
+    /// The addresses of nodes to bootstrap from in the form of `host:port`. The host is resolved
+    /// every time the address is pinged, and the id of the node is learned from its response.
+    pub bootstrap_addrs: Vec<String>,
+    /// The amount of time before a bootstrap attempt that reached no seed is retried. The interval
+    /// doubles after every failed attempt.
+    pub bootstrap_retry_interval: Duration,
+    /// The maximum amount of time between bootstrap attempts.
+    pub max_bootstrap_retry_interval: Duration,
+    /// The maximum number of entries in a k-bucket, and the number of nodes a key-value pair is
+    /// stored on.
+    pub replication_param: usize,
+    /// The depth `b` of the relaxed k-bucket splitting proposed in Section 4.2. A full k-bucket
+    /// whose range does not contain the node's id is also split if its depth is not a multiple of
+    /// `b`, which keeps more contacts in the subtrees next to the node's id. If `None`, only the
+    /// k-bucket that contains the node's id is split. A depth less than 2 has no effect, and is
+    /// rejected by `NodeConfigBuilder::relaxed_splitting`.
+    pub relaxed_splitting: Option<usize>,
+    /// The maximum number of active RPCs during a lookup.
+    pub concurrency_param: usize,
+    /// The amount of time to wait for a response before a request times out.
+    pub request_timeout: Duration,
+    /// The number of consecutive requests to a node that must time out before the node is evicted
+    /// from the routing table.
+    pub max_failures: usize,
+    /// The amount of time without communication after which a node in the routing table is
+    /// questionable. When a k-bucket is full, its questionable nodes are pinged so that
+    /// unresponsive nodes can be replaced by new ones.
+    pub questionable_interval: Duration,
+    /// The amount of time before a key-value pair without a time-to-live expires.
+    pub key_expiration: Duration,
+    /// The maximum amount of time a key-value pair is stored, regardless of its time-to-live.
+    pub max_ttl: Duration,
+    /// The maximum total size in bytes of the values stored by the node. If `None`, the size is not
+    /// limited.
+    pub max_storage_bytes: Option<usize>,
+    /// The maximum number of key-value pairs stored by the node. If `None`, the number is not
+    /// limited.
+    pub max_storage_entries: Option<usize>,
+    /// The policy applied when storing a key-value pair would exceed `max_storage_bytes` or
+    /// `max_storage_entries`.
+    pub eviction_policy: EvictionPolicy,
+    /// The amount of time before a k-bucket that has not been updated is refreshed.
+    pub bucket_refresh_interval: Duration,
+    /// The amount of time between republishes of the key-value pairs inserted by the node. This
+    /// should be shorter than `key_expiration` so that the pairs do not expire.
+    pub republish_interval: Duration,
+    /// The amount of time between replications of the stored key-value pairs to the closest nodes.
+    /// Cached pairs and pairs that were stored within the last interval are not replicated, so a
+    /// pair is only replicated if its time-to-live exceeds this interval. At most
+    /// `concurrency_param` replication lookups are active at a time.
+    pub replication_interval: Duration,
+    /// The maximum length of a datagram in bytes. Larger messages are split into fragments.
+    pub message_length: usize,
+    /// The maximum size of a value in bytes. Messages that carry larger values are rejected.
+    pub max_value_size: usize,
+    /// The number of malformed datagrams an address may send before it is blacklisted. If `None`,
+    /// addresses are never blacklisted.
+    pub malformed_message_limit: Option<usize>,
+    /// The amount of time a blacklisted address is ignored.
+    pub blacklist_duration: Duration,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            id: None,
+            identity_file: None,
+            bootstrap_nodes: Vec::new(),
+            bootstrap_addrs: Vec::new(),
+            bootstrap_retry_interval: Duration::from_secs(BOOTSTRAP_RETRY_INTERVAL),
+            max_bootstrap_retry_interval: Duration::from_secs(MAX_BOOTSTRAP_RETRY_INTERVAL),
+            replication_param: REPLICATION_PARAM,
+            relaxed_splitting: None,
+            concurrency_param: CONCURRENCY_PARAM,
+            request_timeout: Duration::from_millis(REQUEST_TIMEOUT),
+            max_failures: MAX_FAILURES,
+            questionable_interval: Duration::from_secs(QUESTIONABLE_INTERVAL),
+            key_expiration: Duration::from_secs(KEY_EXPIRATION),
+            max_ttl: Duration::from_secs(MAX_TTL),
+            max_storage_bytes: None,
+            max_storage_entries: None,
+            eviction_policy: EvictionPolicy::FurthestFirst,
+            bucket_refresh_interval: Duration::from_secs(BUCKET_REFRESH_INTERVAL),
+            republish_interval: Duration::from_secs(REPUBLISH_INTERVAL),
+            replication_interval: Duration::from_secs(REPLICATION_INTERVAL),
+            message_length: MESSAGE_LENGTH,
+            max_value_size: MAX_VALUE_SIZE,
+            malformed_message_limit: None,
+            blacklist_duration: Duration::from_secs(BLACKLIST_DURATION),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Returns a `NodeConfigBuilder` initialized with the default configuration.
+    pub fn builder() -> NodeConfigBuilder {
+        NodeConfigBuilder::new()
+    }
+}
+
+/// A builder for `NodeConfig`.
+///
+/// # Examples
+///
+/// ```
+/// use kademlia_dht::NodeConfig;
+/// use std::time::Duration;
+///
+/// let config = NodeConfig::builder()
+///     .replication_param(8)
+///     .request_timeout(Duration::from_millis(500))
+///     .build();
+///
+/// assert_eq!(config.replication_param, 8);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct NodeConfigBuilder {
+    config: NodeConfig,
+}
+
+impl NodeConfigBuilder {
+    /// Constructs a new `NodeConfigBuilder` initialized with the default configuration.
+    pub fn new() -> Self {
+        NodeConfigBuilder {
+            config: NodeConfig::default(),
+        }
+    }
+
+    /// Sets the id of the node.
+    pub fn id(mut self, id: Option<Key>) -> Self {
+        self.config.id = id;
+        self
+    }
+
+    /// Sets the file that holds the id of the node.
+    pub fn identity_file(mut self, identity_file: Option<PathBuf>) -> Self {
+        self.config.identity_file = identity_file;
+        self
+    }
+
+    /// Sets the additional nodes to bootstrap from.
+    pub fn bootstrap_nodes(mut self, bootstrap_nodes: Vec<NodeData>) -> Self {
+        self.config.bootstrap_nodes = bootstrap_nodes;
+        self
+    }
+
+    /// Sets the `host:port` addresses of the nodes to bootstrap from.
+    pub fn bootstrap_addrs(mut self, bootstrap_addrs: Vec<String>) -> Self {
+        self.config.bootstrap_addrs = bootstrap_addrs;
+        self
+    }
+
+    /// Sets the amount of time before a failed bootstrap attempt is retried.
+    pub fn bootstrap_retry_interval(mut self, bootstrap_retry_interval: Duration) -> Self {
+        self.config.bootstrap_retry_interval = bootstrap_retry_interval;
+        self
+    }
+
+    /// Sets the maximum amount of time between bootstrap attempts.
+    pub fn max_bootstrap_retry_interval(mut self, max_bootstrap_retry_interval: Duration) -> Self {
+        self.config.max_bootstrap_retry_interval = max_bootstrap_retry_interval;
+        self
+    }
+
+    /// Sets the maximum number of entries in a k-bucket.
+    pub fn replication_param(mut self, replication_param: usize) -> Self {
+        self.config.replication_param = replication_param;
+        self
+    }
+
+    /// Sets the depth of the relaxed k-bucket splitting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the depth is less than 2, since every k-bucket depth is a multiple of 1.
+    pub fn relaxed_splitting(mut self, relaxed_splitting: Option<usize>) -> Self {
+        if let Some(depth) = relaxed_splitting {
+            assert!(
+                depth >= 2,
+                "relaxed splitting depth must be at least 2, got {}",
+                depth
+            );
+        }
+        self.config.relaxed_splitting = relaxed_splitting;
+        self
+    }
+
+    /// Sets the maximum number of active RPCs during a lookup.
+    pub fn concurrency_param(mut self, concurrency_param: usize) -> Self {
+        self.config.concurrency_param = concurrency_param;
+        self
+    }
+
+    /// Sets the amount of time to wait for a response before a request times out.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.config.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the number of consecutive requests to a node that must time out before it is evicted.
+    pub fn max_failures(mut self, max_failures: usize) -> Self {
+        self.config.max_failures = max_failures;
+        self
+    }
+
+    /// Sets the amount of time without communication after which a node is questionable.
+    pub fn questionable_interval(mut self, questionable_interval: Duration) -> Self {
+        self.config.questionable_interval = questionable_interval;
+        self
+    }
+
+    /// Sets the amount of time before a key-value pair without a time-to-live expires.
+    pub fn key_expiration(mut self, key_expiration: Duration) -> Self {
+        self.config.key_expiration = key_expiration;
+        self
+    }
+
+    /// Sets the maximum amount of time a key-value pair is stored.
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.config.max_ttl = max_ttl;
+        self
+    }
+
+    /// Sets the maximum total size in bytes of the values stored by the node.
+    pub fn max_storage_bytes(mut self, max_storage_bytes: Option<usize>) -> Self {
+        self.config.max_storage_bytes = max_storage_bytes;
+        self
+    }
+
+    /// Sets the maximum number of key-value pairs stored by the node.
+    pub fn max_storage_entries(mut self, max_storage_entries: Option<usize>) -> Self {
+        self.config.max_storage_entries = max_storage_entries;
+        self
+    }
+
+    /// Sets the policy applied when storing a key-value pair would exceed the storage quota.
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.config.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// Sets the amount of time before a k-bucket that has not been updated is refreshed.
+    pub fn bucket_refresh_interval(mut self, bucket_refresh_interval: Duration) -> Self {
+        self.config.bucket_refresh_interval = bucket_refresh_interval;
+        self
+    }
+
+    /// Sets the amount of time between republishes of the key-value pairs inserted by the node.
+    pub fn republish_interval(mut self, republish_interval: Duration) -> Self {
+        self.config.republish_interval = republish_interval;
+        self
+    }
+
+    /// Sets the amount of time between replications of the stored key-value pairs.
+    pub fn replication_interval(mut self, replication_interval: Duration) -> Self {
+        self.config.replication_interval = replication_interval;
+        self
+    }
+
+    /// Sets the maximum length of a datagram in bytes.
+    pub fn message_length(mut self, message_length: usize) -> Self {
+        self.config.message_length = message_length;
+        self
+    }
+
+    /// Sets the maximum size of a value in bytes.
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.config.max_value_size = max_value_size;
+        self
+    }
+
+    /// Sets the number of malformed datagrams an address may send before it is blacklisted.
+    pub fn malformed_message_limit(mut self, malformed_message_limit: Option<usize>) -> Self {
+        self.config.malformed_message_limit = malformed_message_limit;
+        self
+    }
+
+    /// Sets the amount of time a blacklisted address is ignored.
+    pub fn blacklist_duration(mut self, blacklist_duration: Duration) -> Self {
+        self.config.blacklist_duration = blacklist_duration;
+        self
+    }
+
+    /// Consumes the builder and returns the `NodeConfig`.
+    pub fn build(self) -> NodeConfig {
+        self.config
+    }
+}
@@ -0,0 +1,90 @@
+use crate::node::node_data::NodeData;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::result;
+use std::string::FromUtf8Error;
+
+/// An enum representing the errors that can occur while operating a node.
+#[derive(Debug)]
+pub enum Error {
+    /// The node could not bind to the requested address.
+    Bind(io::Error),
+    /// An I/O error occurred on the node's socket.
+    Io(io::Error),
+    /// A message could not be serialized or deserialized.
+    Serialization(bincode::Error),
+    /// A request did not receive a response within the request timeout.
+    Timeout,
+    /// The node has been shut down and can no longer send or receive messages.
+    Shutdown,
+    /// A value that was requested as a string is not valid UTF-8.
+    Utf8(FromUtf8Error),
+    /// A value is larger than the maximum value size of the node.
+    ValueTooLarge(usize),
+    /// Fewer nodes than required acknowledged an insert.
+    InsufficientReplicas {
+        /// The minimum number of nodes that had to acknowledge the insert.
+        required: usize,
+        /// The nodes that acknowledged the insert.
+        confirmed: Vec<NodeData>,
+    },
+}
+
+/// A specialized `Result` type for operations on a node.
+pub type Result<T> = result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(err) => write!(f, "could not bind to address: {}", err),
+            Error::Io(err) => write!(f, "i/o error: {}", err),
+            Error::Serialization(err) => write!(f, "could not serialize message: {}", err),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::Shutdown => write!(f, "node has been shut down"),
+            Error::Utf8(err) => write!(f, "value is not valid utf-8: {}", err),
+            Error::ValueTooLarge(size) => write!(f, "value of {} bytes is too large", size),
+            Error::InsufficientReplicas {
+                required,
+                confirmed,
+            } => write!(
+                f,
+                "only {} of the required {} nodes acknowledged the insert",
+                confirmed.len(),
+                required,
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Bind(err) | Error::Io(err) => Some(err),
+            Error::Serialization(err) => Some(err),
+            Error::Utf8(err) => Some(err),
+            Error::Timeout
+            | Error::Shutdown
+            | Error::ValueTooLarge(_)
+            | Error::InsufficientReplicas { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Serialization(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(err: FromUtf8Error) -> Self {
+        Error::Utf8(err)
+    }
+}
@@ -1,7 +1,13 @@
+use crate::error;
 use crate::KEY_LENGTH;
-use rand;
+use rand::Rng;
 use serde_derive::{Deserialize, Serialize};
+use std::cmp;
+use std::ffi::OsString;
 use std::fmt::{Debug, Formatter, Result};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 /// A key that represents nodes and data.
 ///
@@ -24,27 +30,82 @@ impl Key {
     }
 
     /// Constructs a new, random `Key`.
+    #[cfg(test)]
     pub(super) fn rand() -> Self {
+        Key::rand_with(&mut rand::thread_rng())
+    }
+
+    /// Constructs a new, random `Key` using `rng` as the source of randomness.
+    pub(super) fn rand_with<R: Rng>(rng: &mut R) -> Self {
         let mut ret = Key([0; KEY_LENGTH]);
-        for byte in &mut ret.0 {
-            *byte = rand::random::<u8>();
-        }
+        rng.fill_bytes(&mut ret.0);
         ret
     }
 
     /// Constructs a new, random `Key` from `[2^(KEY_LENGTH - index - 1), 2^(KEY_LENGTH - index))`.
+    #[cfg(test)]
     pub(super) fn rand_in_range(index: usize) -> Self {
-        let mut ret = Key::rand();
-        let bytes = index / 8;
-        let bit = index % 8;
-        for i in 0..bytes {
-            ret.0[i] = 0;
+        let prefix = Key::default().with_bit(index);
+        Key::rand_with_prefix(&prefix, index + 1, &mut rand::thread_rng())
+    }
+
+    /// Constructs a new, random `Key` whose first `depth` bits are the same as those of `prefix`
+    /// using `rng` as the source of randomness.
+    pub(super) fn rand_with_prefix<R: Rng>(prefix: &Key, depth: usize, rng: &mut R) -> Self {
+        let mut ret = Key::rand_with(rng);
+        for i in 0..KEY_LENGTH {
+            let bits = cmp::min(depth.saturating_sub(i * 8), 8);
+            let mask = (0xFF00u16 >> bits) as u8;
+            ret.0[i] = (prefix.0[i] & mask) | (ret.0[i] & !mask);
         }
-        ret.0[bytes] &= 0xFF >> (bit);
-        ret.0[bytes] |= 1 << (8 - bit - 1);
         ret
     }
 
+    /// Reads a `Key` from the identity file at `path`. If the file does not exist, a new, random
+    /// `Key` is generated using `rng` and written to it. The file holds the key in hexadecimal.
+    ///
+    /// The new file is written to `<path>.tmp` and synced before it is renamed to `path`, so a
+    /// crash while it is being written never leaves a truncated identity file behind.
+    pub(super) fn load_or_create<R: Rng>(path: &Path, rng: &mut R) -> error::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Key::from_hex(contents.trim()).ok_or_else(|| {
+                let reason = format!("{} is not a valid identity file", path.display());
+                io::Error::new(io::ErrorKind::InvalidData, reason).into()
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = Key::rand_with(rng);
+                let mut temp_path = OsString::from(path.as_os_str());
+                temp_path.push(".tmp");
+                let temp_path = PathBuf::from(temp_path);
+
+                let mut temp_file = File::create(&temp_path)?;
+                temp_file.write_all(format!("{}\n", key.to_hex()).as_bytes())?;
+                temp_file.sync_all()?;
+                fs::rename(&temp_path, path)?;
+                Ok(key)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the hexadecimal representation of `self`, which is parsed by `from_hex`.
+    fn to_hex(self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Parses a `Key` from its hexadecimal representation. Returns `None` if `hex` is not
+    /// `KEY_LENGTH` bytes of hexadecimal.
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != KEY_LENGTH * 2 || !hex.is_ascii() {
+            return None;
+        }
+        let mut ret = Key([0; KEY_LENGTH]);
+        for (i, byte) in ret.0.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(ret)
+    }
+
     /// Returns the XOR result between `self` and `key`.
     pub(super) fn xor(&self, key: &Key) -> Key {
         let mut ret = [0; KEY_LENGTH];
@@ -54,6 +115,19 @@ impl Key {
         Key(ret)
     }
 
+    /// Returns the bit at `index`, where the most significant bit has an index of zero.
+    pub(super) fn bit(&self, index: usize) -> usize {
+        usize::from(self.0[index / 8] >> (7 - index % 8) & 1)
+    }
+
+    /// Returns a copy of `self` with the bit at `index` set, where the most significant bit has an
+    /// index of zero.
+    pub(super) fn with_bit(&self, index: usize) -> Key {
+        let mut ret = *self;
+        ret.0[index / 8] |= 0x80 >> (index % 8);
+        ret
+    }
+
     /// Returns the number of leading zeros in `self`. This is used to calculate the distance
     /// between keys.
     pub(super) fn leading_zeros(&self) -> usize {
@@ -74,6 +148,40 @@ mod tests {
     use super::Key;
     use crate::KEY_LENGTH;
     use num_bigint::BigUint;
+    use std::fs;
+
+    #[test]
+    fn test_load_or_create() {
+        let path = std::env::temp_dir().join(format!("kademlia-dht-{}.id", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut rng = rand::thread_rng();
+        let key = Key::load_or_create(&path, &mut rng).unwrap();
+        assert_eq!(Key::load_or_create(&path, &mut rng).unwrap(), key);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", key.to_hex())
+        );
+        assert_eq!(Key::from_hex(&key.to_hex()), Some(key));
+
+        fs::write(&path, "not a key").unwrap();
+        assert!(Key::load_or_create(&path, &mut rng).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_or_create_after_interrupted_write() {
+        let path = std::env::temp_dir().join(format!("kademlia-dht-{}-tmp.id", std::process::id()));
+        let temp_path = path.with_extension("id.tmp");
+        let _ = fs::remove_file(&path);
+        // a write that was interrupted before the rename leaves only the temporary file
+        fs::write(&temp_path, "0123").unwrap();
+
+        let mut rng = rand::thread_rng();
+        let key = Key::load_or_create(&path, &mut rng).unwrap();
+        assert_eq!(Key::load_or_create(&path, &mut rng).unwrap(), key);
+        assert!(!temp_path.exists());
+        fs::remove_file(&path).unwrap();
+    }
 
     #[test]
     fn test_rand_in_range() {
@@ -20,8 +20,6 @@
 //!
 //! use kademlia_dht::{Key, Node};
 //! use sha3::{Digest, Sha3_256};
-//! use std::thread;
-//! use std::time::Duration;
 //!
 //! fn clone_into_array<A, T>(slice: &[T]) -> A
 //! where
@@ -39,18 +37,16 @@
 //!     Key(clone_into_array(hasher.result().as_slice()))
 //! }
 //!
-//! fn main() {
-//!     let mut node = Node::new("localhost", "8080", None);
+//! fn main() -> Result<(), kademlia_dht::Error> {
+//!     let mut node = Node::new("localhost", "8080", None)?;
 //!
 //!     let key = get_key("Hello");
 //!     let value = "World";
 //!
-//!     node.insert(key, value);
+//!     node.insert_str(key, value)?;
 //!
-//!     // inserting is asynchronous, so sleep for a second
-//!     thread::sleep(Duration::from_millis(1000));
-//!
-//!     assert_eq!(node.get(&key).unwrap(), value);
+//!     assert_eq!(node.get_str(&key)?.unwrap(), value);
+//!     Ok(())
 //! }
 //! ```
 //!
@@ -69,18 +65,38 @@
 //! extern crate kademlia_dht;
 //! ```
 //!
+//! ## Features
+//!
+//! - `async`: enables `AsyncNode`, a node with a `Future`-based API that is driven by a Tokio task
+//!   over a non-blocking socket. It depends on `tokio`.
+//! - `bench`: exposes the internals used by the benchmarks in `benches`. It is not part of the
+//!   public API.
+//! - `file-storage`: enables `FileStorage`, a `StorageBackend` that persists key-value pairs to an
+//!   append-only log so that they survive restarts.
+//!
 //! ## Design Considerations
 //!
 //! - Many of the paper's original optimizations were not implemented due to their complexity for
 //!   arguably little gain.
-//! - Each node's routing table uses a growable vector to represent the binary tree of k-buckets.
-//!   The vector grows as the k-bucket closest to the node's ID exceeds capacity. The relaxation of
-//!   k-bucket splitting proposed in Section 4.2 is not implemented.
-//! - Caching and key republishing described in Section 2.5 is not implemented to simplify the
-//!   number of moving parts and active threads. It is up to the user of the library to ensure that
-//!   keys are being republished.
+//! - Each node's routing table is a binary trie of k-buckets keyed by prefix. A leaf is split as
+//!   the k-bucket that contains the node's ID exceeds capacity, and the closest nodes to a key are
+//!   found by walking the trie in XOR order. The relaxation of k-bucket splitting proposed in
+//!   Section 4.2 is enabled by `NodeConfig::relaxed_splitting`.
+//! - As described in Section 4.1, a node seen while its k-bucket is full is kept in a replacement
+//!   cache. In the style of BEP 5, the least recently seen questionable node of the k-bucket, one
+//!   that has failed a request or has not been seen in `questionable_interval`, is pinged. It is
+//!   evicted and replaced from the cache once `max_failures` consecutive requests to it fail.
+//! - As described in Section 2.5, a value found by a lookup is cached on the closest node on the
+//!   lookup path that did not return it. The cached pair expires after `key_expiration` halved for
+//!   every known node that is closer to the key. The original publisher of a key-value pair
+//!   republishes it every `republish_interval` until `Node::stop_republishing` is called, and
+//!   every node replicates the pairs that it did not only cache every `replication_interval`.
+//!   Replicated and cached pairs keep their remaining lifetime, so a pair expires unless its
+//!   publisher republishes it.
 //! - The recursive lookup of nodes uses strict parallelism to tightly bound the number of active
 //!   RPCs rather than the loose parallelism implied by the paper.
+//! - Each node uses two threads: one that receives datagrams, and an event loop that handles all
+//!   requests, responses, and timeouts. The number of threads does not grow with the request rate.
 //! - Each key is 256 bits as opposed to 160 bits so that consumers can use SHA-3 instead of SHA-1.
 //!
 //! ## Changelog
@@ -102,36 +118,86 @@
 
 #![warn(missing_docs)]
 
+mod clock;
+mod config;
+mod error;
 mod key;
 mod node;
 mod protocol;
 mod routing;
+pub mod sim;
 mod storage;
+mod transport;
 
+pub use self::config::{EvictionPolicy, NodeConfig, NodeConfigBuilder};
+pub use self::error::{Error, Result};
 pub use self::key::Key;
+#[cfg(feature = "async")]
+pub use self::node::async_node::AsyncNode;
 pub use self::node::node_data::NodeData;
 pub use self::node::Node;
+pub use self::protocol::ProtocolStats;
+pub use self::routing::RoutingSnapshot;
+#[cfg(feature = "file-storage")]
+pub use self::storage::FileStorage;
+pub use self::storage::{MemoryStorage, StorageBackend, StorageEntry, StorageStats};
+pub use self::transport::{MemoryNetwork, MemoryTransport, Transport};
+
+// Exposed for the benchmarks in `benches`.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use self::clock::SystemClock;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use self::routing::RoutingTable;
 
 /// The number of bytes in a key.
 const KEY_LENGTH: usize = 32;
 
-/// The maximum length of the message in bytes.
+/// The default maximum length of the message in bytes.
 const MESSAGE_LENGTH: usize = 8196;
 
-/// The maximum number of k-buckets in the routing table.
+/// The default maximum size of a value in bytes.
+const MAX_VALUE_SIZE: usize = 65536;
+
+/// The number of bits in a key, which bounds the depth of the binary trie of k-buckets.
 const ROUTING_TABLE_SIZE: usize = KEY_LENGTH * 8;
 
-/// The maximum number of entries in a k-bucket.
+/// The default maximum number of entries in a k-bucket.
 const REPLICATION_PARAM: usize = 20;
 
-/// The maximum number of active RPCs during `lookup_nodes`.
+/// The default maximum number of active RPCs during `lookup_nodes`.
 const CONCURRENCY_PARAM: usize = 3;
 
-/// Request timeout time in milliseconds
+/// The default request timeout time in milliseconds.
 const REQUEST_TIMEOUT: u64 = 5000;
 
-/// Key-value pair expiration time in seconds
+/// The default key-value pair expiration time in seconds.
 const KEY_EXPIRATION: u64 = 3600;
 
-/// Bucket refresh interval in seconds
+/// The default bucket refresh interval in seconds.
 const BUCKET_REFRESH_INTERVAL: u64 = 3600;
+
+/// The default maximum time-to-live of a key-value pair in seconds.
+const MAX_TTL: u64 = 86400;
+
+/// The default interval at which stored key-value pairs are replicated in seconds.
+const REPLICATION_INTERVAL: u64 = 3600;
+
+/// The default interval at which the original publisher republishes a key-value pair in seconds.
+const REPUBLISH_INTERVAL: u64 = 3000;
+
+/// The default amount of time before a failed bootstrap attempt is retried in seconds.
+const BOOTSTRAP_RETRY_INTERVAL: u64 = 5;
+
+/// The default maximum amount of time between bootstrap attempts in seconds.
+const MAX_BOOTSTRAP_RETRY_INTERVAL: u64 = 300;
+
+/// The default amount of time a blacklisted address is ignored in seconds.
+const BLACKLIST_DURATION: u64 = 600;
+
+/// The default number of consecutive timed out requests after which a node is evicted.
+const MAX_FAILURES: usize = 3;
+
+/// The default amount of time without communication after which a node is questionable in seconds.
+const QUESTIONABLE_INTERVAL: u64 = 900;
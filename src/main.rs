@@ -1,11 +1,11 @@
-use log::{info, log};
+use log::info;
 use sha3::{Digest, Sha3_256};
 use simplelog::{CombinedLogger, Config, Level, LevelFilter, TermLogger};
 use std::collections::HashMap;
 use std::convert::AsMut;
 use std::io;
 
-use kademlia_dht::{Key, Node};
+use kademlia_dht::{Key, MemoryNetwork, Node, NodeConfig};
 
 fn clone_into_array<A, T>(slice: &[T]) -> A
 where
@@ -36,18 +36,20 @@ fn main() {
     ])
     .unwrap();
 
+    let network = MemoryNetwork::new();
     let mut node_map = HashMap::new();
     let mut id = 0;
     for i in 0..50 {
         if i == 0 {
-            let n = Node::new(&"localhost".to_string(), &(8900 + i).to_string(), None);
+            let n = Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
             node_map.insert(id, n.clone());
         } else {
-            let n = Node::new(
-                &"localhost".to_string(),
-                &(8900 + i).to_string(),
+            let n = Node::with_transport(
+                network.bind(),
                 Some(node_map[&0].node_data()),
-            );
+                NodeConfig::default(),
+            )
+            .unwrap();
             node_map.insert(id, n.clone());
         }
         id += 1;
@@ -69,24 +71,33 @@ fn main() {
         match args[0] {
             "new" => {
                 let index: u32 = args[1].parse().unwrap();
-                let node = Node::new(
-                    &"localhost".to_string(),
-                    &(8900 + id).to_string(),
+                match Node::with_transport(
+                    network.bind(),
                     Some(node_map[&index].node_data()),
-                );
-                node_map.insert(id, node);
+                    NodeConfig::default(),
+                ) {
+                    Ok(node) => {
+                        node_map.insert(id, node);
+                    }
+                    Err(err) => {
+                        info!("{}", err);
+                        continue;
+                    }
+                }
                 id += 1;
             }
             "insert" => {
                 let index: u32 = args[1].parse().unwrap();
                 let key = get_key(args[2]);
                 let value = args[3];
-                node_map.get_mut(&index).unwrap().insert(key, value);
+                if let Err(err) = node_map.get_mut(&index).unwrap().insert_str(key, value) {
+                    info!("{}", err);
+                }
             }
             "get" => {
                 let index: u32 = args[1].parse().unwrap();
                 let key = get_key(args[2]);
-                info!("{:?}", node_map.get_mut(&index).unwrap().get(&key));
+                info!("{:?}", node_map.get_mut(&index).unwrap().get_str(&key));
             }
             _ => {}
         }
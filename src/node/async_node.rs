@@ -0,0 +1,496 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::NodeConfig;
+use crate::error::{Error, Result};
+use crate::key::Key;
+use crate::node::check_replicas;
+use crate::node::core::Event;
+use crate::node::event_loop::{new_core, Callback, Command, Driver, Input};
+use crate::node::node_data::NodeData;
+use crate::protocol::{encode_datagrams, Decoder, Message, Metrics, ProtocolStats};
+use crate::routing::RoutingSnapshot;
+use crate::storage::{MemoryStorage, StorageBackend};
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{self, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+/// A Tokio task that drives a `NodeCore`.
+///
+/// The task waits on the node's socket, on the commands sent by its `AsyncNode` handles, and on
+/// the next deadline of the `NodeCore`, whichever comes first. Messages to addresses that are not
+/// a literal `SocketAddr`, such as seed hostnames, are sent from a task of their own so that a slow
+/// DNS lookup does not stall the event loop.
+struct EventLoop {
+    driver: Driver,
+    socket: Arc<UdpSocket>,
+    decoder: Decoder,
+    clock: Arc<dyn Clock>,
+    rx: UnboundedReceiver<Input>,
+    next_fragment_id: u64,
+    config: Arc<NodeConfig>,
+}
+
+impl EventLoop {
+    async fn run(mut self) {
+        // one extra byte to detect datagrams that exceed the maximum message length
+        let mut buffer = vec![0u8; self.config.message_length + 1];
+        loop {
+            let timeout = (self.driver.core.next_deadline() - self.clock.now())
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            tokio::select! {
+                received = self.socket.recv_from(&mut buffer) => match received {
+                    Ok((len, src_addr)) => {
+                        if let Some(message) = self.decoder.decode(&buffer[..len], src_addr) {
+                            self.driver.core.handle_message(message);
+                        }
+                    }
+                    Err(err) => warn!("Protocol: Could not receive data: {}", err),
+                },
+                input = self.rx.recv() => match input {
+                    Some(Input::Command(command)) => self.driver.handle_command(command),
+                    Some(Input::Message(_)) | None => break,
+                },
+                _ = tokio::time::sleep(timeout) => {}
+            }
+            self.driver.core.handle_timeouts();
+            self.flush().await;
+        }
+        info!("{} - Killed event loop", self.driver.core.node_data().addr);
+    }
+
+    /// Sends the outgoing messages of the `NodeCore` and calls the callbacks of its completed
+    /// operations.
+    async fn flush(&mut self) {
+        while let Some((message, dest)) = self.driver.core.poll_transmit() {
+            if let Err(err) = self.send_message(&message, &dest).await {
+                warn!(
+                    "{} - Could not send message to {}: {}",
+                    self.driver.core.node_data().addr,
+                    dest.addr,
+                    err,
+                );
+            }
+        }
+        self.driver.dispatch_events();
+    }
+
+    async fn send_message(&mut self, message: &Message, node_data: &NodeData) -> Result<()> {
+        let id = self.next_fragment_id;
+        self.next_fragment_id = self.next_fragment_id.wrapping_add(1);
+        let datagrams = encode_datagrams(message, id, &self.config)?;
+        if let Ok(dest) = node_data.addr.parse() {
+            return send_datagrams(&self.socket, datagrams, dest).await;
+        }
+
+        let socket = Arc::clone(&self.socket);
+        let addr = node_data.addr.clone();
+        tokio::spawn(async move {
+            let result = match resolve(&socket, &addr).await {
+                Ok(dest) => send_datagrams(&socket, datagrams, dest).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("Could not send message to {}: {}", addr, err);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Resolves `addr` to an address of the same family as the address of `socket`.
+async fn resolve(socket: &UdpSocket, addr: &str) -> Result<SocketAddr> {
+    let local_addr = socket.local_addr()?;
+    let dest = net::lookup_host(addr)
+        .await?
+        .find(|dest| dest.is_ipv4() == local_addr.is_ipv4())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve address"))?;
+    Ok(dest)
+}
+
+async fn send_datagrams(
+    socket: &UdpSocket,
+    datagrams: Vec<Vec<u8>>,
+    dest: SocketAddr,
+) -> Result<()> {
+    for datagram in datagrams {
+        socket.send_to(&datagram, dest).await?;
+    }
+    Ok(())
+}
+
+/// A node in the Kademlia DHT with a `Future`-based API.
+///
+/// The node is driven by a Tokio task that owns its socket, so no thread is spawned for the node
+/// or for its requests. The node must be constructed within a Tokio runtime, which runs the task
+/// until the node is killed.
+///
+/// This type is only available with the `async` feature.
+#[derive(Clone)]
+pub struct AsyncNode {
+    tx: UnboundedSender<Input>,
+    node_data: Arc<NodeData>,
+    metrics: Arc<Metrics>,
+    is_bootstrapped: Arc<AtomicBool>,
+    config: Arc<NodeConfig>,
+}
+
+impl AsyncNode {
+    /// Constructs a new `AsyncNode` on a specific ip and port, and bootstraps the node with an
+    /// existing node if `bootstrap` is not `None`. The node uses the default `NodeConfig`.
+    ///
+    /// Returns an error if the node could not bind to the address, or if the identity file in
+    /// `config` could not be read or written.
+    pub async fn new(ip: &str, port: &str, bootstrap: Option<NodeData>) -> Result<Self> {
+        AsyncNode::with_config(ip, port, bootstrap, NodeConfig::default()).await
+    }
+
+    /// Constructs a new `AsyncNode` on a specific ip and port with the protocol parameters in
+    /// `config`, and bootstraps the node with an existing node if `bootstrap` is not `None`.
+    ///
+    /// Returns an error if the node could not bind to the address, or if the identity file in
+    /// `config` could not be read or written.
+    pub async fn with_config(
+        ip: &str,
+        port: &str,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+    ) -> Result<Self> {
+        let addr = format!("{}:{}", ip, port);
+        let socket = UdpSocket::bind(addr).await.map_err(Error::Bind)?;
+        AsyncNode::with_socket(socket, bootstrap, config).await
+    }
+
+    /// Constructs a new `AsyncNode` that communicates over `socket` with the protocol parameters
+    /// in `config`, and bootstraps the node with an existing node if `bootstrap` is not `None`.
+    /// The node stores its key-value pairs in a `MemoryStorage`.
+    ///
+    /// Returns an error if the address of the socket could not be determined, or if the identity
+    /// file in `config` could not be read or written.
+    pub async fn with_socket(
+        socket: UdpSocket,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+    ) -> Result<Self> {
+        AsyncNode::with_storage(socket, bootstrap, config, MemoryStorage::new()).await
+    }
+
+    /// Constructs a new `AsyncNode` that communicates over `socket` with the protocol parameters
+    /// in `config` and stores its key-value pairs in `storage`, and bootstraps the node with an
+    /// existing node if `bootstrap` is not `None`.
+    ///
+    /// Returns an error if the address of the socket could not be determined, or if the identity
+    /// file in `config` could not be read or written.
+    pub async fn with_storage<S>(
+        socket: UdpSocket,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+        storage: S,
+    ) -> Result<Self>
+    where
+        S: StorageBackend + 'static,
+    {
+        let node = AsyncNode::spawn(socket, config, Box::new(storage), None)?;
+        node.execute(|callback| Command::Bootstrap(bootstrap, callback))
+            .await?;
+        Ok(node)
+    }
+
+    /// Constructs a new `AsyncNode` with the id in `snapshot`, rather than the id in `config`, that
+    /// communicates over `socket` with the protocol parameters in `config` and stores its
+    /// key-value pairs in `storage`. The node bootstraps from the contacts in `snapshot`, each of
+    /// which is pinged so that the contacts that no longer respond are removed from the routing
+    /// table.
+    ///
+    /// Returns an error if the address of the socket could not be determined.
+    pub async fn from_snapshot<S>(
+        socket: UdpSocket,
+        snapshot: RoutingSnapshot,
+        config: NodeConfig,
+        storage: S,
+    ) -> Result<Self>
+    where
+        S: StorageBackend + 'static,
+    {
+        let node = AsyncNode::spawn(socket, config, Box::new(storage), Some(snapshot.id))?;
+        node.execute(|callback| Command::Restore(snapshot.nodes, callback))
+            .await?;
+        Ok(node)
+    }
+
+    /// Spawns the task that drives the node on the current Tokio runtime.
+    fn spawn(
+        socket: UdpSocket,
+        config: NodeConfig,
+        storage: Box<dyn StorageBackend>,
+        id: Option<Key>,
+    ) -> Result<Self> {
+        let addr = socket.local_addr()?.to_string();
+        let config = Arc::new(config);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let core = new_core(addr, Arc::clone(&config), Arc::clone(&clock), storage, id)?;
+        let node_data = Arc::new(core.node_data().clone());
+        let metrics = Arc::new(Metrics::default());
+        let is_bootstrapped = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let event_loop = EventLoop {
+            driver: Driver::new(core, Arc::clone(&is_bootstrapped)),
+            socket: Arc::new(socket),
+            decoder: Decoder::new(
+                Arc::clone(&metrics),
+                Arc::clone(&config),
+                Arc::clone(&clock),
+            ),
+            clock,
+            rx,
+            next_fragment_id: 0,
+            config: Arc::clone(&config),
+        };
+        tokio::spawn(event_loop.run());
+
+        Ok(AsyncNode {
+            tx,
+            node_data,
+            metrics,
+            is_bootstrapped,
+            config,
+        })
+    }
+
+    /// Sends `command` to the event loop. Returns an error if the event loop has stopped, or if
+    /// the value to insert is larger than `max_value_size`.
+    fn send(&self, command: Command) -> Result<()> {
+        command.check_value_size(self.config.max_value_size)?;
+        self.tx
+            .send(Input::Command(command))
+            .map_err(|_| Error::Shutdown)
+    }
+
+    /// Sends the command returned by `command` to the event loop, and resolves to the event of
+    /// the operation once it completes, or to `Error::Shutdown` if the event loop stopped first.
+    async fn execute<F>(&self, command: F) -> Result<Event>
+    where
+        F: FnOnce(Callback) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send(command(Box::new(move |event| {
+            let _ = tx.send(event);
+        })))?;
+        rx.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Inserts a key-value pair into the DHT, and resolves once the closest nodes to the key have
+    /// acknowledged or failed to acknowledge the `STORE` RPC. Resolves to the nodes that
+    /// acknowledged it, ordered by distance to the key. The node republishes the pair every
+    /// `republish_interval` until `stop_republishing` is called.
+    ///
+    /// Returns an error if the node has been shut down or if the value is larger than
+    /// `max_value_size`.
+    pub async fn put(&self, key: Key, value: &[u8]) -> Result<Vec<NodeData>> {
+        self.put_with_min_replicas(key, value, 0).await
+    }
+
+    /// Inserts a key-value pair into the DHT like `put`, but returns
+    /// `Error::InsufficientReplicas` if fewer than `min_replicas` nodes acknowledged the `STORE`
+    /// RPC.
+    pub async fn put_with_min_replicas(
+        &self,
+        key: Key,
+        value: &[u8],
+        min_replicas: usize,
+    ) -> Result<Vec<NodeData>> {
+        self.put_item(key, value, None, min_replicas).await
+    }
+
+    /// Inserts a key-value pair into the DHT like `put`, but the pair expires after `ttl` instead
+    /// of after the receivers' `key_expiration`. The time-to-live is capped by the receivers'
+    /// `max_ttl`, and the node stops republishing the pair once it has elapsed.
+    pub async fn put_with_ttl(
+        &self,
+        key: Key,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<Vec<NodeData>> {
+        self.put_item(key, value, Some(ttl), 0).await
+    }
+
+    async fn put_item(
+        &self,
+        key: Key,
+        value: &[u8],
+        ttl: Option<Duration>,
+        min_replicas: usize,
+    ) -> Result<Vec<NodeData>> {
+        let value = value.to_vec();
+        let command = |callback| Command::Insert(key, value, ttl, min_replicas, callback);
+        match self.execute(command).await? {
+            Event::Inserted(_, confirmed) => check_replicas(confirmed, min_replicas),
+            event => unreachable!("Unexpected event for an insert: {:?}", event),
+        }
+    }
+
+    /// Stops republishing the key-value pair with `key` that was inserted by this node. The nodes
+    /// that store the pair keep replicating it until its time-to-live elapses.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub fn stop_republishing(&self, key: &Key) -> Result<()> {
+        self.send(Command::StopRepublishing(*key))
+    }
+
+    /// Inserts a key-value pair with a string value into the DHT. The value is stored as its UTF-8
+    /// bytes. Resolves to the nodes that acknowledged the `STORE` RPC.
+    ///
+    /// Returns an error if the node has been shut down or if the value is larger than
+    /// `max_value_size`.
+    pub async fn put_str(&self, key: Key, value: &str) -> Result<Vec<NodeData>> {
+        self.put(key, value.as_bytes()).await
+    }
+
+    /// Gets the value associated with a particular key in the DHT. Returns `None` if the key was
+    /// not found.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub async fn get(&self, key: &Key) -> Result<Option<Vec<u8>>> {
+        match self
+            .execute(|callback| Command::Get(*key, callback))
+            .await?
+        {
+            Event::FoundValue(_, value) => Ok(value),
+            event => unreachable!("Unexpected event for a get: {:?}", event),
+        }
+    }
+
+    /// Gets the value associated with a particular key in the DHT as a string. Returns `None` if
+    /// the key was not found.
+    ///
+    /// Returns an error if the node has been shut down or if the value is not valid UTF-8.
+    pub async fn get_str(&self, key: &Key) -> Result<Option<String>> {
+        match self.get(key).await? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the closest nodes to `key` in the DHT.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub async fn find_node(&self, key: &Key) -> Result<Vec<NodeData>> {
+        match self
+            .execute(|callback| Command::FindNode(*key, callback))
+            .await?
+        {
+            Event::FoundNodes(_, nodes) => Ok(nodes),
+            event => unreachable!("Unexpected event for a node lookup: {:?}", event),
+        }
+    }
+
+    /// Returns a snapshot of the node's id and routing table, which can be used to restart the
+    /// node with `AsyncNode::from_snapshot`.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub async fn snapshot(&self) -> Result<RoutingSnapshot> {
+        match self.execute(Command::Snapshot).await? {
+            Event::Snapshot(_, snapshot) => Ok(snapshot),
+            event => unreachable!("Unexpected event for a snapshot: {:?}", event),
+        }
+    }
+
+    /// Returns the `NodeData` associated with the node.
+    pub fn node_data(&self) -> NodeData {
+        (*self.node_data).clone()
+    }
+
+    /// Returns `true` once a bootstrap attempt received a response from at least one node, or
+    /// finished without any seeds. A node that could not reach any seed keeps retrying in the
+    /// background with exponential backoff.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.is_bootstrapped.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the counters describing the datagrams received by the node.
+    pub fn protocol_stats(&self) -> ProtocolStats {
+        self.metrics.snapshot()
+    }
+
+    /// Kills the current node and its event loop. The socket is closed once the event loop and the
+    /// messages it is still resolving addresses for have stopped, and operations that did not complete resolve to `Error::Shutdown`.
+    pub fn kill(&self) {
+        let _ = self.tx.send(Input::Message(Message::Kill));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncNode;
+    use crate::config::NodeConfig;
+    use crate::key::Key;
+
+    #[tokio::test]
+    async fn test_put_get_find_node() {
+        let bootstrap = AsyncNode::with_config("127.0.0.1", "0", None, NodeConfig::default())
+            .await
+            .unwrap();
+        let mut nodes = vec![bootstrap.clone()];
+        for _ in 0..20 {
+            let node = AsyncNode::with_config(
+                "127.0.0.1",
+                "0",
+                Some(bootstrap.node_data()),
+                NodeConfig::default(),
+            )
+            .await
+            .unwrap();
+            nodes.push(node);
+        }
+
+        let key = Key::new([7; 32]);
+        nodes[3].put_str(key, "value").await.unwrap();
+
+        assert_eq!(
+            nodes[17].get_str(&key).await.unwrap(),
+            Some("value".to_string())
+        );
+
+        let target = nodes[5].node_data();
+        let closest = nodes[11].find_node(&target.id).await.unwrap();
+        assert_eq!(closest.first(), Some(&target));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_from_hostname() {
+        let seed = AsyncNode::with_config("127.0.0.1", "0", None, NodeConfig::default())
+            .await
+            .unwrap();
+        let port = seed
+            .node_data()
+            .addr
+            .rsplit(':')
+            .next()
+            .unwrap()
+            .to_string();
+        let config = NodeConfig::builder()
+            .bootstrap_addrs(vec![format!("localhost:{}", port)])
+            .build();
+        let node = AsyncNode::with_config("127.0.0.1", "0", None, config)
+            .await
+            .unwrap();
+        assert!(node.is_bootstrapped());
+        let closest = node.find_node(&seed.node_data().id).await.unwrap();
+        assert_eq!(closest.first(), Some(&seed.node_data()));
+    }
+
+    #[tokio::test]
+    async fn test_killed_node_returns_shutdown() {
+        let node = AsyncNode::with_config("127.0.0.1", "0", None, NodeConfig::default())
+            .await
+            .unwrap();
+        node.kill();
+        assert!(node.get(&Key::new([0; 32])).await.is_err());
+    }
+}
@@ -0,0 +1,1289 @@
+use crate::clock::Clock;
+use crate::config::{EvictionPolicy, NodeConfig};
+use crate::error::{Error, Result};
+use crate::key::Key;
+use crate::node::lookup::Lookup;
+use crate::node::node_data::NodeData;
+use crate::protocol::{Message, Request, RequestPayload, Response, ResponsePayload};
+use crate::routing::{RoutingSnapshot, RoutingTable};
+use crate::storage::StorageBackend;
+use log::{debug, info, warn};
+use rand::StdRng;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use time::{Duration, SteadyTime};
+
+/// An identifier for an operation started on a `NodeCore`.
+pub type OperationId = u64;
+
+/// An event emitted by a `NodeCore` when an operation completes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The node finished an attempt to bootstrap its routing table. The attempt may have failed to
+    /// reach any seed, in which case the node retries later; see `NodeCore::is_bootstrapped`.
+    Bootstrapped(OperationId),
+    /// The closest nodes to a key were found.
+    FoundNodes(OperationId, Vec<NodeData>),
+    /// A value lookup finished. The value is `None` if the key was not found.
+    FoundValue(OperationId, Option<Vec<u8>>),
+    /// The `STORE` RPCs of an insert completed. The nodes are the closest nodes to the key that
+    /// acknowledged the `STORE` RPC, ordered by distance to the key.
+    Inserted(OperationId, Vec<NodeData>),
+    /// A snapshot of the node's id and routing table was taken.
+    Snapshot(OperationId, RoutingSnapshot),
+}
+
+/// Evicts key-value pairs according to `eviction_policy` until a pair with `key` and a value of
+/// `size` bytes fits in the storage quota. Returns `false` if the pair should be rejected instead,
+/// in which case nothing is evicted.
+///
+/// Returns an error if an evicted pair could not be removed from storage.
+fn make_room(
+    storage: &mut dyn StorageBackend,
+    config: &NodeConfig,
+    node_id: &Key,
+    key: &Key,
+    size: usize,
+) -> Result<bool> {
+    let max_bytes = config.max_storage_bytes.unwrap_or(usize::MAX);
+    let max_entries = config.max_storage_entries.unwrap_or(usize::MAX);
+    if size > max_bytes || max_entries == 0 {
+        return Ok(false);
+    }
+
+    // expired pairs must not count toward the quota
+    storage.expire();
+    let stats = storage.stats();
+    let old_size = storage.get(key).map(|(value, _)| value.len());
+    let mut bytes = stats.bytes - old_size.unwrap_or(0) + size;
+    let mut entries = stats.entries + if old_size.is_some() { 0 } else { 1 };
+    if bytes <= max_bytes && entries <= max_entries {
+        return Ok(true);
+    }
+
+    let mut candidates: Vec<_> = storage
+        .iter()
+        .filter(|entry| entry.key != *key)
+        .map(|entry| (entry.key, entry.value.len(), entry.age))
+        .collect();
+    match config.eviction_policy {
+        EvictionPolicy::FurthestFirst => {
+            candidates.sort_by_key(|(candidate, _, _)| Reverse(candidate.xor(node_id)))
+        }
+        EvictionPolicy::OldestFirst => candidates.sort_by_key(|(_, _, age)| Reverse(*age)),
+        EvictionPolicy::Reject => return Ok(false),
+    }
+
+    let mut evicted = Vec::new();
+    for (candidate, candidate_size, _) in candidates {
+        if bytes <= max_bytes && entries <= max_entries {
+            break;
+        }
+        if config.eviction_policy == EvictionPolicy::FurthestFirst
+            && candidate.xor(node_id) < key.xor(node_id)
+        {
+            return Ok(false);
+        }
+        bytes -= candidate_size;
+        entries -= 1;
+        evicted.push(candidate);
+    }
+    for candidate in evicted {
+        info!("Evicted {:?}", candidate);
+        storage.remove(&candidate)?;
+    }
+    Ok(true)
+}
+
+/// Computes the response to a request RPC. The keys of the pairs that are only stored because
+/// they were cached are tracked in `cached_keys`.
+///
+/// Returns an error if the storage could not be updated, in which case the request is rejected.
+fn respond(
+    payload: RequestPayload,
+    routing_table: &RoutingTable,
+    storage: &mut dyn StorageBackend,
+    cached_keys: &mut HashSet<Key>,
+    config: &NodeConfig,
+    node_id: &Key,
+) -> Result<ResponsePayload> {
+    let payload = match payload {
+        RequestPayload::Ping => ResponsePayload::Pong,
+        RequestPayload::Store(key, value, ttl) => {
+            if !make_room(storage, config, node_id, &key, value.len())? {
+                return Ok(ResponsePayload::StoreRejected);
+            }
+            let ttl = ttl.unwrap_or(config.key_expiration);
+            storage.insert(key, value, ttl.min(config.max_ttl))?;
+            cached_keys.remove(&key);
+            ResponsePayload::Pong
+        }
+        RequestPayload::CacheStore(key, value, ttl) => {
+            if !make_room(storage, config, node_id, &key, value.len())? {
+                return Ok(ResponsePayload::StoreRejected);
+            }
+            // a cached pair never shortens the lifetime of a stored pair with the same key
+            let mut ttl = ttl.min(config.key_expiration).min(config.max_ttl);
+            let stored = storage.get(&key);
+            if let Some((_, remaining)) = stored {
+                ttl = ttl.max(remaining);
+            }
+            storage.insert(key, value, ttl)?;
+            // a stored pair stays stored rather than cached
+            if stored.is_none() {
+                cached_keys.insert(key);
+            }
+            ResponsePayload::Pong
+        }
+        RequestPayload::FindNode(key) => {
+            ResponsePayload::Nodes(routing_table.get_closest_nodes(&key, config.replication_param))
+        }
+        RequestPayload::FindValue(key) => match storage.get(&key) {
+            Some((value, remaining)) => ResponsePayload::Value(value, remaining),
+            None => ResponsePayload::Nodes(
+                routing_table.get_closest_nodes(&key, config.replication_param),
+            ),
+        },
+    };
+    Ok(payload)
+}
+
+/// The reason a lookup was started, which determines what happens when it finishes.
+enum LookupPurpose {
+    /// A lookup of the node's own id during the bootstrap attempt with the given id.
+    Bootstrap(OperationId),
+    BootstrapRefresh(OperationId),
+    Refresh,
+    FindNode,
+    Get,
+    /// Stores the value on the closest nodes and records it as published if at least
+    /// `min_replicas` nodes acknowledge it.
+    Insert(Vec<u8>, Option<std::time::Duration>, usize),
+    /// Stores the value on the closest nodes without emitting an event. Used to republish and to
+    /// replicate key-value pairs.
+    Replicate(Vec<u8>, Option<std::time::Duration>),
+}
+
+/// The reason a request was sent, which determines what happens when it completes.
+enum RequestPurpose {
+    Lookup(OperationId),
+    /// A ping to a questionable node of a full bucket. The ping is repeated until the node responds
+    /// or has failed `max_failures` requests and is evicted.
+    EvictionPing(NodeData),
+    Store(OperationId),
+    /// A `STORE` RPC that replicates or caches a key-value pair. Its response is ignored.
+    Replicate,
+    /// A ping to a seed address, whose id is not known until it responds. The response adds the
+    /// seed to the routing table.
+    SeedPing(OperationId),
+    /// A ping to a contact restored from a `RoutingSnapshot`. The contact stays in the routing
+    /// table if it responds, and is removed if the ping times out.
+    Revalidate(NodeData),
+}
+
+struct PendingRequest {
+    dest: NodeData,
+    sent_at: SteadyTime,
+    deadline: SteadyTime,
+    purpose: RequestPurpose,
+}
+
+/// A key-value pair inserted by this node.
+struct PublishedItem {
+    value: Vec<u8>,
+    expires_at: Option<SteadyTime>,
+    republish_at: SteadyTime,
+}
+
+struct PendingInsert {
+    key: Key,
+    item: PublishedItem,
+    min_replicas: usize,
+    remaining: usize,
+    confirmed: Vec<NodeData>,
+}
+
+struct PendingLookup {
+    lookup: Lookup,
+    find_node: bool,
+    key: Key,
+    purpose: LookupPurpose,
+}
+
+/// The protocol state of a node without any I/O or threads.
+///
+/// `NodeCore` is driven by passing it received messages through `handle_message`, and by calling
+/// `handle_timeouts` once the time returned by `next_deadline` has passed. The messages it wants to
+/// send are retrieved with `poll_transmit`, and completed operations with `poll_event`. Since all
+/// time is read from its `Clock` and all randomness comes from its seeded RNG, a `NodeCore` behaves
+/// deterministically given the same inputs.
+pub struct NodeCore {
+    node_data: Arc<NodeData>,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
+    rng: StdRng,
+    routing_table: RoutingTable,
+    eviction_pings: HashSet<Key>,
+    storage: Box<dyn StorageBackend>,
+    cached_keys: HashSet<Key>,
+    replication_queue: VecDeque<Key>,
+    replications: HashSet<OperationId>,
+    pending_requests: HashMap<Key, PendingRequest>,
+    request_deadlines: BTreeSet<(SteadyTime, Key)>,
+    lookups: HashMap<OperationId, PendingLookup>,
+    bootstraps: HashMap<OperationId, usize>,
+    seeds: Vec<NodeData>,
+    seed_pings: HashMap<OperationId, usize>,
+    is_bootstrapped: bool,
+    has_responses: bool,
+    bootstrap_retry_interval: std::time::Duration,
+    next_bootstrap: Option<SteadyTime>,
+    inserts: HashMap<OperationId, PendingInsert>,
+    published: HashMap<Key, PublishedItem>,
+    republish_deadlines: BTreeSet<(SteadyTime, Key)>,
+    next_operation_id: OperationId,
+    next_refresh: SteadyTime,
+    next_replication: SteadyTime,
+    transmits: VecDeque<(Message, NodeData)>,
+    events: VecDeque<Event>,
+}
+
+impl NodeCore {
+    /// Constructs a new `NodeCore` with `id` that is reachable at `addr`, and that stores its
+    /// key-value pairs in `storage`.
+    pub fn new(
+        addr: String,
+        id: Key,
+        config: Arc<NodeConfig>,
+        clock: Arc<dyn Clock>,
+        rng: StdRng,
+        storage: Box<dyn StorageBackend>,
+    ) -> Self {
+        let node_data = Arc::new(NodeData { addr, id });
+        let routing_table = RoutingTable::new(
+            Arc::clone(&node_data),
+            Arc::clone(&config),
+            Arc::clone(&clock),
+        );
+        let bootstrap_retry_interval = config.bootstrap_retry_interval;
+        let next_refresh = clock.now() + to_duration(config.bucket_refresh_interval);
+        let next_replication = clock.now() + to_duration(config.replication_interval);
+        NodeCore {
+            node_data,
+            config,
+            clock,
+            rng,
+            routing_table,
+            eviction_pings: HashSet::new(),
+            storage,
+            cached_keys: HashSet::new(),
+            replication_queue: VecDeque::new(),
+            replications: HashSet::new(),
+            pending_requests: HashMap::new(),
+            request_deadlines: BTreeSet::new(),
+            lookups: HashMap::new(),
+            bootstraps: HashMap::new(),
+            seeds: Vec::new(),
+            seed_pings: HashMap::new(),
+            is_bootstrapped: false,
+            has_responses: false,
+            bootstrap_retry_interval,
+            next_bootstrap: None,
+            inserts: HashMap::new(),
+            published: HashMap::new(),
+            republish_deadlines: BTreeSet::new(),
+            next_operation_id: 0,
+            next_refresh,
+            next_replication,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Returns the `NodeData` associated with the node.
+    pub fn node_data(&self) -> &NodeData {
+        &self.node_data
+    }
+
+    /// Bootstraps the routing table using `bootstrap` and the seeds in `bootstrap_nodes` and
+    /// `bootstrap_addrs`. The node first pings the seed addresses to learn their ids, and looks up
+    /// its id to identify the closest nodes to it. Then it refreshes all routing buckets by looking
+    /// up a random key in the buckets' range. `Event::Bootstrapped` is emitted once all lookups
+    /// finish.
+    ///
+    /// If no seed responded, the attempt is retried after `bootstrap_retry_interval`, which doubles
+    /// after every failed attempt up to `max_bootstrap_retry_interval`.
+    pub fn bootstrap(&mut self, bootstrap: Option<NodeData>) -> OperationId {
+        self.start_bootstrap(bootstrap.into_iter().collect())
+    }
+
+    fn start_bootstrap(&mut self, mut seeds: Vec<NodeData>) -> OperationId {
+        seeds.extend(self.config.bootstrap_nodes.iter().cloned());
+        seeds.retain(|seed| seed.id != self.node_data.id);
+        self.seeds = seeds;
+        self.bootstrap_retry_interval = self.config.bootstrap_retry_interval;
+        self.next_bootstrap = None;
+        let operation_id = self.next_operation_id();
+        self.attempt_bootstrap(operation_id);
+        operation_id
+    }
+
+    fn attempt_bootstrap(&mut self, operation_id: OperationId) {
+        self.has_responses = false;
+        for seed in self.seeds.clone() {
+            self.routing_table.update_node(seed);
+        }
+        let seed_addrs = self.config.bootstrap_addrs.clone();
+        if seed_addrs.is_empty() {
+            let id = self.node_data.id;
+            self.start_lookup(id, true, LookupPurpose::Bootstrap(operation_id));
+            return;
+        }
+        self.seed_pings.insert(operation_id, seed_addrs.len());
+        for addr in seed_addrs {
+            let dest = NodeData {
+                addr,
+                id: Key::default(),
+            };
+            let purpose = RequestPurpose::SeedPing(operation_id);
+            self.send_request(dest, RequestPayload::Ping, purpose);
+        }
+    }
+
+    /// Marks the node as bootstrapped if a node responded during the attempt or if it has no seeds.
+    /// Otherwise, schedules another bootstrap attempt. The seeds are in the routing table even if
+    /// they are unreachable, so a non-empty routing table does not mean that the attempt succeeded.
+    fn finish_bootstrap(&mut self, operation_id: OperationId) {
+        let has_seeds = !self.seeds.is_empty() || !self.config.bootstrap_addrs.is_empty();
+        if !has_seeds || self.has_responses {
+            self.is_bootstrapped = true;
+        } else {
+            warn!(
+                "{} - Could not reach any seed; retrying in {:?}",
+                self.node_data.addr, self.bootstrap_retry_interval,
+            );
+            let retry_interval = self.bootstrap_retry_interval;
+            self.next_bootstrap = Some(self.clock.now() + to_duration(retry_interval));
+            self.bootstrap_retry_interval =
+                (retry_interval * 2).min(self.config.max_bootstrap_retry_interval);
+        }
+        self.events.push_back(Event::Bootstrapped(operation_id));
+    }
+
+    /// Returns `true` once a bootstrap attempt received a response from at least one node, or
+    /// finished without any seeds to bootstrap from.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.is_bootstrapped
+    }
+
+    /// Bootstraps the routing table using the contacts in a `RoutingSnapshot`. Each contact is
+    /// added to the routing table and pinged, so that the contacts that no longer respond are
+    /// removed. The node then bootstraps like `bootstrap`, and `Event::Bootstrapped` is emitted
+    /// once all lookups finish.
+    pub fn restore(&mut self, nodes: Vec<NodeData>) -> OperationId {
+        for node_data in &nodes {
+            if node_data.id == self.node_data.id {
+                continue;
+            }
+            self.routing_table.update_node(node_data.clone());
+            self.send_request(
+                node_data.clone(),
+                RequestPayload::Ping,
+                RequestPurpose::Revalidate(node_data.clone()),
+            );
+        }
+        self.start_bootstrap(nodes)
+    }
+
+    /// Takes a snapshot of the node's id and routing table. `Event::Snapshot` is emitted
+    /// immediately.
+    pub fn snapshot(&mut self) -> OperationId {
+        let operation_id = self.next_operation_id();
+        let snapshot = RoutingSnapshot {
+            id: self.node_data.id,
+            nodes: self.routing_table.nodes(),
+        };
+        self.events
+            .push_back(Event::Snapshot(operation_id, snapshot));
+        operation_id
+    }
+
+    /// Starts a lookup of the closest nodes to `key`. `Event::FoundNodes` is emitted once it
+    /// finishes.
+    pub fn find_node(&mut self, key: Key) -> OperationId {
+        self.start_lookup(key, true, LookupPurpose::FindNode)
+    }
+
+    /// Starts a lookup of the value associated with `key`. `Event::FoundValue` is emitted once it
+    /// finishes.
+    pub fn get(&mut self, key: Key) -> OperationId {
+        self.start_lookup(key, false, LookupPurpose::Get)
+    }
+
+    /// Starts inserting a key-value pair into the DHT that expires after `ttl`, or after the
+    /// receivers' `key_expiration` if `ttl` is `None`. `Event::Inserted` is emitted once every
+    /// `STORE` RPC has been acknowledged or has timed out.
+    ///
+    /// If at least `min_replicas` nodes acknowledge the pair, it is recorded as published by this
+    /// node, and is republished every `republish_interval` until `stop_republishing` is called or
+    /// until its time-to-live elapses.
+    pub fn insert(
+        &mut self,
+        key: Key,
+        value: Vec<u8>,
+        ttl: Option<std::time::Duration>,
+        min_replicas: usize,
+    ) -> OperationId {
+        self.stop_republishing(&key);
+        self.start_lookup(key, true, LookupPurpose::Insert(value, ttl, min_replicas))
+    }
+
+    /// Stops republishing the key-value pair with `key`. Returns `false` if the node was not
+    /// republishing the key.
+    pub fn stop_republishing(&mut self, key: &Key) -> bool {
+        match self.published.remove(key) {
+            Some(published_item) => {
+                self.republish_deadlines
+                    .remove(&(published_item.republish_at, *key));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a message received from another node.
+    pub fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Request(request) => self.handle_request(request),
+            Message::Response(response) => self.handle_response(response),
+            Message::Kill | Message::Fragment(_) => {}
+        }
+    }
+
+    /// Fails all requests whose deadline has passed, republishes the key-value pairs that are due,
+    /// replicates the stored key-value pairs if the replication interval has elapsed, and refreshes
+    /// stale routing buckets if the refresh interval has elapsed.
+    pub fn handle_timeouts(&mut self) {
+        let now = self.clock.now();
+        while let Some(&(deadline, token)) = self.request_deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.request_deadlines.remove(&(deadline, token));
+            if let Some(pending_request) = self.pending_requests.remove(&token) {
+                warn!(
+                    "{} - Request to {} timed out after waiting for {} milliseconds",
+                    self.node_data.addr,
+                    pending_request.dest.addr,
+                    self.config.request_timeout.as_millis(),
+                );
+                self.routing_table.record_failure(&pending_request.dest);
+                self.complete_request(pending_request.purpose, Err(Error::Timeout));
+            }
+        }
+
+        while let Some(&(deadline, key)) = self.republish_deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.republish_deadlines.remove(&(deadline, key));
+            let republish_at = now + to_duration(self.config.republish_interval);
+            let (value, ttl) = match self.published.get_mut(&key) {
+                Some(published_item) => {
+                    published_item.republish_at = republish_at;
+                    let ttl = published_item
+                        .expires_at
+                        .map(|expires_at| to_std(expires_at - now));
+                    (published_item.value.clone(), ttl)
+                }
+                None => continue,
+            };
+            // a pair whose time-to-live has elapsed is no longer republished
+            if ttl == Some(std::time::Duration::from_secs(0)) {
+                self.published.remove(&key);
+                continue;
+            }
+            self.republish_deadlines.insert((republish_at, key));
+            self.start_lookup(key, true, LookupPurpose::Replicate(value, ttl));
+        }
+
+        if self.next_replication <= now {
+            self.replicate_items();
+            self.next_replication = now + to_duration(self.config.replication_interval);
+        }
+
+        if let Some(next_bootstrap) = self.next_bootstrap {
+            if next_bootstrap <= now {
+                self.next_bootstrap = None;
+                let operation_id = self.next_operation_id();
+                self.attempt_bootstrap(operation_id);
+            }
+        }
+
+        if self.next_refresh <= now {
+            for (prefix, depth) in self.routing_table.get_stale_prefixes() {
+                let key = Key::rand_with_prefix(&prefix, depth, &mut self.rng);
+                self.start_lookup(key, true, LookupPurpose::Refresh);
+            }
+            self.next_refresh = now + to_duration(self.config.bucket_refresh_interval);
+        }
+    }
+
+    /// Returns the earliest time at which `handle_timeouts` needs to be called.
+    pub fn next_deadline(&self) -> SteadyTime {
+        let request_deadline = self.request_deadlines.iter().next();
+        let republish_deadline = self.republish_deadlines.iter().next();
+        request_deadline
+            .into_iter()
+            .chain(republish_deadline)
+            .map(|&(deadline, _)| deadline)
+            .chain(self.next_bootstrap)
+            .fold(
+                self.next_refresh.min(self.next_replication),
+                SteadyTime::min,
+            )
+    }
+
+    /// Returns the next message to send and its destination.
+    pub fn poll_transmit(&mut self) -> Option<(Message, NodeData)> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next completed operation.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn handle_request(&mut self, request: Request) {
+        info!(
+            "{} - Receiving request from {} {:#?}",
+            self.node_data.addr, request.sender.addr, request.payload,
+        );
+        self.update_routing_table(request.sender.clone());
+        let payload = respond(
+            request.payload.clone(),
+            &self.routing_table,
+            self.storage.as_mut(),
+            &mut self.cached_keys,
+            &self.config,
+            &self.node_data.id,
+        )
+        .unwrap_or_else(|err| {
+            warn!(
+                "{} - Could not update storage for request from {}: {}",
+                self.node_data.addr, request.sender.addr, err,
+            );
+            ResponsePayload::StoreRejected
+        });
+        let dest = request.sender.clone();
+        let response = Message::Response(Response {
+            request,
+            receiver: (*self.node_data).clone(),
+            payload,
+        });
+        self.transmits.push_back((response, dest));
+    }
+
+    fn handle_response(&mut self, response: Response) {
+        self.update_routing_table(response.receiver.clone());
+        match self.pending_requests.remove(&response.request.id) {
+            Some(pending_request) => {
+                info!(
+                    "{} - Receiving response from {} {:#?}",
+                    self.node_data.addr, response.receiver.addr, response.payload,
+                );
+                self.has_responses = true;
+                self.request_deadlines
+                    .remove(&(pending_request.deadline, response.request.id));
+                let rtt = self.clock.now() - pending_request.sent_at;
+                self.routing_table.record_rtt(&response.receiver, rtt);
+                if let Some(srtt) = self.routing_table.rtt(&response.receiver) {
+                    debug!(
+                        "{} - Round-trip time to {} is {} milliseconds",
+                        self.node_data.addr,
+                        response.receiver.addr,
+                        srtt.num_milliseconds(),
+                    );
+                }
+                self.complete_request(pending_request.purpose, Ok(response));
+            }
+            None => warn!(
+                "{} - Original request not found; irrelevant response or expired request.",
+                self.node_data.addr
+            ),
+        }
+    }
+
+    /// Caches a value found by a lookup on `dest`, the closest node on the lookup path that did not
+    /// return the value. The time-to-live of the cached pair is halved for each of the
+    /// `closer_nodes` that are closer to the key than `dest`, and never exceeds the `remaining`
+    /// lifetime of the value.
+    fn cache_value(
+        &mut self,
+        dest: NodeData,
+        key: Key,
+        value: Vec<u8>,
+        remaining: std::time::Duration,
+        closer_nodes: usize,
+    ) {
+        let ttl = self.config.key_expiration / 2u32.pow(closer_nodes.min(31) as u32);
+        let ttl = ttl.min(remaining);
+        let payload = RequestPayload::CacheStore(key, value, ttl);
+        self.send_request(dest, payload, RequestPurpose::Replicate);
+    }
+
+    /// Queues every key-value pair in storage to be stored on the current closest nodes to its
+    /// key. Cached pairs are not replicated, and as an optimization, pairs that were stored within
+    /// the last replication interval are skipped, since the node that sent the `STORE` RPC is
+    /// assumed to have replicated it to the other closest nodes as well. Pairs that were queued in
+    /// the previous round but not yet replicated are dropped from the queue.
+    fn replicate_items(&mut self) {
+        self.storage.expire();
+        let replication_interval = self.config.replication_interval;
+        let mut keys = Vec::new();
+        let mut stored_keys = HashSet::new();
+        for entry in self.storage.iter() {
+            stored_keys.insert(entry.key);
+            if entry.age >= replication_interval && !self.cached_keys.contains(&entry.key) {
+                keys.push(entry.key);
+            }
+        }
+        self.cached_keys.retain(|key| stored_keys.contains(key));
+        keys.sort();
+        self.replication_queue = keys.into();
+        self.start_replications();
+    }
+
+    /// Starts replicating the queued key-value pairs until `concurrency_param` replication lookups
+    /// are active. The replicated pairs keep their remaining lifetime.
+    fn start_replications(&mut self) {
+        while self.replications.len() < self.config.concurrency_param {
+            let key = match self.replication_queue.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            let (value, remaining) = match self.storage.get(&key) {
+                Some(ret) => ret,
+                None => continue,
+            };
+            let purpose = LookupPurpose::Replicate(value, Some(remaining));
+            let operation_id = self.start_lookup(key, true, purpose);
+            // the lookup finishes immediately if the routing table is empty
+            if self.lookups.contains_key(&operation_id) {
+                self.replications.insert(operation_id);
+            }
+        }
+    }
+
+    /// Upserts the routing table. If the node cannot be inserted into the routing table, it is kept
+    /// in the bucket's replacement cache and the least recently seen questionable node of the
+    /// bucket is pinged. If that node fails `max_failures` pings, it is evicted and replaced from
+    /// the cache. If the bucket has no questionable nodes, the bucket is left unchanged.
+    fn update_routing_table(&mut self, node_data: NodeData) {
+        debug!("{} updating {}", self.node_data.addr, node_data.addr);
+        if !self.routing_table.update_node(node_data.clone()) {
+            if let Some(questionable_node) = self.routing_table.questionable(&node_data.id) {
+                if self.eviction_pings.insert(questionable_node.id) {
+                    self.send_request(
+                        questionable_node.clone(),
+                        RequestPayload::Ping,
+                        RequestPurpose::EvictionPing(questionable_node),
+                    );
+                }
+            }
+        }
+    }
+
+    fn send_request(&mut self, dest: NodeData, payload: RequestPayload, purpose: RequestPurpose) {
+        info!(
+            "{} - Sending request to {} {:#?}",
+            self.node_data.addr, dest.addr, payload
+        );
+        let mut token = Key::rand_with(&mut self.rng);
+        while self.pending_requests.contains_key(&token) {
+            token = Key::rand_with(&mut self.rng);
+        }
+        let sent_at = self.clock.now();
+        let deadline = sent_at + to_duration(self.config.request_timeout);
+        let request = Message::Request(Request {
+            id: token,
+            sender: (*self.node_data).clone(),
+            payload,
+        });
+        self.transmits.push_back((request, dest.clone()));
+        self.request_deadlines.insert((deadline, token));
+        self.pending_requests.insert(
+            token,
+            PendingRequest {
+                dest,
+                sent_at,
+                deadline,
+                purpose,
+            },
+        );
+    }
+
+    fn complete_request(&mut self, purpose: RequestPurpose, response: Result<Response>) {
+        match purpose {
+            RequestPurpose::Lookup(operation_id) => {
+                if let Some(pending_lookup) = self.lookups.get_mut(&operation_id) {
+                    pending_lookup.lookup.handle_response(response);
+                    self.advance_lookup(operation_id);
+                }
+            }
+            RequestPurpose::EvictionPing(node_data) => {
+                if response.is_err() && self.routing_table.contains(&node_data) {
+                    self.send_request(
+                        node_data.clone(),
+                        RequestPayload::Ping,
+                        RequestPurpose::EvictionPing(node_data),
+                    );
+                } else {
+                    self.eviction_pings.remove(&node_data.id);
+                }
+            }
+            RequestPurpose::Revalidate(node_data) => {
+                if response.is_err() {
+                    self.routing_table.remove_node(&node_data);
+                }
+            }
+            RequestPurpose::Replicate => {}
+            RequestPurpose::SeedPing(operation_id) => {
+                let is_done = match self.seed_pings.get_mut(&operation_id) {
+                    Some(remaining) => {
+                        *remaining -= 1;
+                        *remaining == 0
+                    }
+                    None => false,
+                };
+                if is_done {
+                    self.seed_pings.remove(&operation_id);
+                    let id = self.node_data.id;
+                    self.start_lookup(id, true, LookupPurpose::Bootstrap(operation_id));
+                }
+            }
+            RequestPurpose::Store(operation_id) => {
+                let is_done = match self.inserts.get_mut(&operation_id) {
+                    Some(pending_insert) => {
+                        if let Ok(Response {
+                            receiver,
+                            payload: ResponsePayload::Pong,
+                            ..
+                        }) = response
+                        {
+                            pending_insert.confirmed.push(receiver);
+                        }
+                        pending_insert.remaining -= 1;
+                        pending_insert.remaining == 0
+                    }
+                    None => false,
+                };
+                if is_done {
+                    if let Some(pending_insert) = self.inserts.remove(&operation_id) {
+                        self.finish_insert(operation_id, pending_insert);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish_insert(&mut self, operation_id: OperationId, pending_insert: PendingInsert) {
+        let PendingInsert {
+            key,
+            item,
+            min_replicas,
+            mut confirmed,
+            ..
+        } = pending_insert;
+        if confirmed.len() >= min_replicas {
+            self.republish_deadlines.insert((item.republish_at, key));
+            self.published.insert(key, item);
+        }
+        confirmed.sort_by_key(|node_data| node_data.id.xor(&key));
+        self.events
+            .push_back(Event::Inserted(operation_id, confirmed));
+    }
+
+    fn next_operation_id(&mut self) -> OperationId {
+        let ret = self.next_operation_id;
+        self.next_operation_id += 1;
+        ret
+    }
+
+    fn start_lookup(&mut self, key: Key, find_node: bool, purpose: LookupPurpose) -> OperationId {
+        let operation_id = self.next_operation_id();
+        let closest_nodes = self
+            .routing_table
+            .get_closest_nodes(&key, self.config.concurrency_param);
+        let lookup = Lookup::new(&self.node_data, key, closest_nodes, &self.config);
+        self.lookups.insert(
+            operation_id,
+            PendingLookup {
+                lookup,
+                find_node,
+                key,
+                purpose,
+            },
+        );
+        self.advance_lookup(operation_id);
+        operation_id
+    }
+
+    fn advance_lookup(&mut self, operation_id: OperationId) {
+        let (dests, key, find_node, result) = match self.lookups.get_mut(&operation_id) {
+            Some(pending_lookup) => (
+                pending_lookup.lookup.next_queries(),
+                pending_lookup.key,
+                pending_lookup.find_node,
+                pending_lookup.lookup.take_result(),
+            ),
+            None => return,
+        };
+
+        for dest in dests {
+            let payload = if find_node {
+                RequestPayload::FindNode(key)
+            } else {
+                RequestPayload::FindValue(key)
+            };
+            self.send_request(dest, payload, RequestPurpose::Lookup(operation_id));
+        }
+
+        if let Some(result) = result {
+            if let Some(pending_lookup) = self.lookups.remove(&operation_id) {
+                self.finish_lookup(operation_id, pending_lookup, result);
+            }
+        }
+    }
+
+    fn finish_lookup(
+        &mut self,
+        operation_id: OperationId,
+        pending_lookup: PendingLookup,
+        result: ResponsePayload,
+    ) {
+        let PendingLookup {
+            lookup,
+            key,
+            purpose,
+            ..
+        } = pending_lookup;
+        if self.replications.remove(&operation_id) {
+            self.start_replications();
+        }
+        match purpose {
+            LookupPurpose::Bootstrap(operation_id) => {
+                let prefixes = self.routing_table.prefixes();
+                self.bootstraps.insert(operation_id, prefixes.len());
+                for (prefix, depth) in prefixes {
+                    let key = Key::rand_with_prefix(&prefix, depth, &mut self.rng);
+                    self.start_lookup(key, true, LookupPurpose::BootstrapRefresh(operation_id));
+                }
+            }
+            LookupPurpose::BootstrapRefresh(bootstrap_id) => {
+                let is_done = match self.bootstraps.get_mut(&bootstrap_id) {
+                    Some(remaining) => {
+                        *remaining -= 1;
+                        *remaining == 0
+                    }
+                    None => false,
+                };
+                if is_done {
+                    self.bootstraps.remove(&bootstrap_id);
+                    self.finish_bootstrap(bootstrap_id);
+                }
+            }
+            LookupPurpose::Refresh => {}
+            LookupPurpose::FindNode => {
+                let nodes = match result {
+                    ResponsePayload::Nodes(nodes) => nodes,
+                    _ => Vec::new(),
+                };
+                self.events
+                    .push_back(Event::FoundNodes(operation_id, nodes));
+            }
+            LookupPurpose::Get => {
+                let value = match result {
+                    ResponsePayload::Value(value, remaining) => {
+                        if let Some((dest, closer_nodes)) = lookup.cache_target() {
+                            self.cache_value(dest, key, value.clone(), remaining, closer_nodes);
+                        }
+                        Some(value)
+                    }
+                    _ => None,
+                };
+                self.events
+                    .push_back(Event::FoundValue(operation_id, value));
+            }
+            LookupPurpose::Insert(value, ttl, min_replicas) => {
+                let nodes = match result {
+                    ResponsePayload::Nodes(nodes) => nodes,
+                    _ => Vec::new(),
+                };
+                let now = self.clock.now();
+                let pending_insert = PendingInsert {
+                    key,
+                    item: PublishedItem {
+                        value: value.clone(),
+                        expires_at: ttl.map(|ttl| now + to_duration(ttl)),
+                        republish_at: now + to_duration(self.config.republish_interval),
+                    },
+                    min_replicas,
+                    remaining: nodes.len(),
+                    confirmed: Vec::new(),
+                };
+                if nodes.is_empty() {
+                    self.finish_insert(operation_id, pending_insert);
+                    return;
+                }
+                self.inserts.insert(operation_id, pending_insert);
+                for dest in nodes {
+                    let payload = RequestPayload::Store(key, value.clone(), ttl);
+                    self.send_request(dest, payload, RequestPurpose::Store(operation_id));
+                }
+            }
+            LookupPurpose::Replicate(value, ttl) => {
+                if let ResponsePayload::Nodes(nodes) = result {
+                    for dest in nodes {
+                        let payload = RequestPayload::Store(key, value.clone(), ttl);
+                        self.send_request(dest, payload, RequestPurpose::Replicate);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Converts a `std::time::Duration` into a `time::Duration`.
+fn to_duration(duration: std::time::Duration) -> Duration {
+    Duration::milliseconds(duration.as_millis() as i64)
+}
+
+/// Converts a `time::Duration` into a `std::time::Duration`. Negative durations become zero.
+fn to_std(duration: Duration) -> std::time::Duration {
+    duration
+        .to_std()
+        .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{make_room, NodeCore};
+    use crate::config::{EvictionPolicy, NodeConfig};
+    use crate::error::Result;
+    use crate::key::Key;
+    use crate::node::node_data::NodeData;
+    use crate::protocol::{Message, Request, RequestPayload, Response, ResponsePayload};
+    use crate::sim::{Simulation, VirtualClock};
+    use crate::storage::{MemoryStorage, StorageBackend, StorageEntry, StorageStats};
+    use rand::{SeedableRng, StdRng};
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A `StorageBackend` that cannot persist any change.
+    struct FailingStorage;
+
+    impl StorageBackend for FailingStorage {
+        fn insert(&mut self, _: Key, _: Vec<u8>, _: Duration) -> Result<()> {
+            Err(io::Error::other("disk full").into())
+        }
+
+        fn get(&mut self, _: &Key) -> Option<(Vec<u8>, Duration)> {
+            None
+        }
+
+        fn remove(&mut self, _: &Key) -> Result<Option<Vec<u8>>> {
+            Err(io::Error::other("disk full").into())
+        }
+
+        fn iter(&mut self) -> Box<dyn Iterator<Item = StorageEntry> + '_> {
+            Box::new(std::iter::empty())
+        }
+
+        fn expire(&mut self) -> usize {
+            0
+        }
+
+        fn stats(&self) -> StorageStats {
+            StorageStats::default()
+        }
+    }
+
+    fn store(storage: &mut MemoryStorage, config: &NodeConfig, key: Key) -> bool {
+        let is_stored = make_room(storage, config, &Key::new([0; 32]), &key, 5).unwrap();
+        if is_stored {
+            storage
+                .insert(key, b"value".to_vec(), Duration::from_secs(60))
+                .unwrap();
+        }
+        is_stored
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let (near, far, farthest) = (Key::new([1; 32]), Key::new([2; 32]), Key::new([3; 32]));
+        let clock = Arc::new(VirtualClock::new());
+        let config = NodeConfig::builder().max_storage_entries(Some(2)).build();
+
+        let mut storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        assert!(store(&mut storage, &config, far));
+        assert!(store(&mut storage, &config, farthest));
+        assert!(store(&mut storage, &config, near));
+        assert!(storage.get(&farthest).is_none());
+        // a pair that would be the furthest is rejected
+        assert!(!store(&mut storage, &config, farthest));
+        assert_eq!(storage.stats().entries, 2);
+
+        let config = NodeConfig::builder()
+            .max_storage_entries(Some(2))
+            .eviction_policy(EvictionPolicy::OldestFirst)
+            .build();
+        let mut storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        assert!(store(&mut storage, &config, near));
+        clock.set_elapsed(Duration::from_secs(1));
+        assert!(store(&mut storage, &config, far));
+        assert!(store(&mut storage, &config, farthest));
+        assert!(storage.get(&near).is_none());
+        // replacing a stored pair does not need room for another entry
+        assert!(store(&mut storage, &config, far));
+        assert!(storage.get(&farthest).is_some());
+
+        let config = NodeConfig::builder()
+            .max_storage_bytes(Some(10))
+            .eviction_policy(EvictionPolicy::Reject)
+            .build();
+        let mut storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        assert!(store(&mut storage, &config, far));
+        assert!(store(&mut storage, &config, farthest));
+        assert!(!store(&mut storage, &config, near));
+        assert!(storage.get(&far).is_some());
+    }
+
+    #[test]
+    fn test_expired_pairs_do_not_count_toward_quota() {
+        let (near, far, farthest) = (Key::new([1; 32]), Key::new([2; 32]), Key::new([3; 32]));
+        let clock = Arc::new(VirtualClock::new());
+        let config = NodeConfig::builder().max_storage_bytes(Some(10)).build();
+        let mut storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        assert!(store(&mut storage, &config, far));
+        assert!(make_room(&mut storage, &config, &Key::new([0; 32]), &farthest, 5).unwrap());
+        storage
+            .insert(farthest, b"value".to_vec(), Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(storage.stats().bytes, 10);
+
+        // `near` fits once `farthest` has expired, so `far` is not evicted
+        clock.set_elapsed(Duration::from_secs(11));
+        assert!(store(&mut storage, &config, near));
+        assert!(storage.get(&far).is_some());
+        assert_eq!(storage.stats().bytes, 10);
+    }
+
+    #[test]
+    fn test_failed_insert_is_not_republished() {
+        let (mut sim, _, nodes) = Simulation::with_nodes(8, 5, &NodeConfig::default());
+        let (key, other_key) = (Key::new([1; 32]), Key::new([2; 32]));
+
+        let confirmed = sim.insert_with_min_replicas(nodes[0], key, b"value", 10);
+        assert_eq!(confirmed.len(), 6);
+        assert!(!sim.stop_republishing(nodes[0], key));
+
+        sim.insert_with_min_replicas(nodes[0], other_key, b"value", 6);
+        assert!(sim.stop_republishing(nodes[0], other_key));
+    }
+
+    #[test]
+    fn test_dead_seed_is_not_bootstrapped() {
+        let mut sim = Simulation::new(9);
+        let seed = NodeData {
+            addr: "10.9.9.9:8080".to_string(),
+            id: Key::new([9; 32]),
+        };
+        let config = NodeConfig::builder().bootstrap_nodes(vec![seed]).build();
+        let node = sim.add_node(None, config);
+        assert!(!sim.is_bootstrapped(node));
+
+        // every attempt pings the dead seed again
+        let messages_sent = sim.stats().messages_sent;
+        sim.run_for(Duration::from_secs(120));
+        assert!(!sim.is_bootstrapped(node));
+        assert!(sim.stats().messages_sent > messages_sent);
+    }
+
+    fn node_core(
+        config: NodeConfig,
+        clock: Arc<VirtualClock>,
+        storage: Box<dyn StorageBackend>,
+    ) -> NodeCore {
+        NodeCore::new(
+            "10.0.0.1:8080".to_string(),
+            Key::new([0; 32]),
+            Arc::new(config),
+            clock,
+            StdRng::from_seed(&[0]),
+            storage,
+        )
+    }
+
+    /// Returns a request from the node at 10.0.0.2.
+    fn request(id: Key, payload: RequestPayload) -> Message {
+        Message::Request(Request {
+            id,
+            sender: NodeData {
+                addr: "10.0.0.2:8080".to_string(),
+                id: Key::new([0xFF; 32]),
+            },
+            payload,
+        })
+    }
+
+    #[test]
+    fn test_store_not_acknowledged_if_not_persisted() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut core = node_core(NodeConfig::default(), clock, Box::new(FailingStorage));
+        let payload = RequestPayload::Store(Key::new([3; 32]), b"value".to_vec(), None);
+        core.handle_message(request(Key::new([1; 32]), payload));
+        match core.poll_transmit() {
+            Some((
+                Message::Response(Response {
+                    payload: ResponsePayload::StoreRejected,
+                    ..
+                }),
+                _,
+            )) => {}
+            other => panic!("unexpected transmit {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replicate_stored_pairs_only() {
+        let clock = Arc::new(VirtualClock::new());
+        let config = NodeConfig::builder()
+            .replication_interval(Duration::from_secs(60))
+            .build();
+        let storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        let mut core = node_core(config, Arc::clone(&clock), Box::new(storage));
+        for i in 1..6 {
+            let payload = RequestPayload::Store(Key::new([i; 32]), b"value".to_vec(), None);
+            core.handle_message(request(Key::new([i; 32]), payload));
+        }
+        let ttl = Duration::from_secs(3600);
+        let payload = RequestPayload::CacheStore(Key::new([0; 32]), b"value".to_vec(), ttl);
+        core.handle_message(request(Key::new([0; 32]), payload));
+        while core.poll_transmit().is_some() {}
+
+        clock.set_elapsed(Duration::from_secs(60));
+        core.handle_timeouts();
+        let mut keys = Vec::new();
+        while let Some((message, _)) = core.poll_transmit() {
+            if let Message::Request(Request {
+                payload: RequestPayload::FindNode(key),
+                ..
+            }) = message
+            {
+                keys.push(key);
+            }
+        }
+        // the cached pair is skipped, and only `concurrency_param` lookups are started
+        let expected: Vec<_> = (1..4).map(|i| Key::new([i; 32])).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_republish_until_stopped() {
+        let config = NodeConfig::builder()
+            .key_expiration(Duration::from_secs(60))
+            .republish_interval(Duration::from_secs(50))
+            .build();
+        let (mut sim, _, nodes) = Simulation::with_nodes(2, 10, &config);
+        let key = Key::new([5; 32]);
+        sim.insert(nodes[0], key, b"value");
+
+        sim.run_for(Duration::from_secs(200));
+        assert_eq!(sim.get(nodes[9], key), Some(b"value".to_vec()));
+
+        assert!(sim.stop_republishing(nodes[0], key));
+        assert!(!sim.stop_republishing(nodes[0], key));
+        sim.run_for(Duration::from_secs(200));
+        assert_eq!(sim.get(nodes[9], key), None);
+    }
+
+    #[test]
+    fn test_replication_outlives_holders() {
+        let config = NodeConfig::builder()
+            .replication_param(3)
+            .replication_interval(Duration::from_secs(20))
+            .build();
+        let (mut sim, bootstrap, mut nodes) = Simulation::with_nodes(3, 10, &config);
+        nodes.push(bootstrap);
+        let key = Key::new([6; 32]);
+        let holders: Vec<_> = sim
+            .insert(nodes[0], key, b"value")
+            .iter()
+            .filter_map(|node_data| nodes.iter().find(|&&id| sim.node_data(id) == *node_data))
+            .cloned()
+            .collect();
+        assert_eq!(holders.len(), 3);
+
+        // the remaining holders replicate the pair to the new closest nodes before they leave
+        for holder in &holders {
+            sim.remove_node(*holder);
+            sim.run_for(Duration::from_secs(60));
+        }
+
+        let node = nodes.iter().find(|id| !holders.contains(id)).unwrap();
+        assert_eq!(sim.get(*node, key), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_replication_keeps_ttl() {
+        let config = NodeConfig::builder()
+            .replication_interval(Duration::from_secs(20))
+            .build();
+        let (mut sim, _, nodes) = Simulation::with_nodes(4, 10, &config);
+        let (key, ttl_key) = (Key::new([7; 32]), Key::new([8; 32]));
+        sim.insert(nodes[0], key, b"value");
+        sim.insert_with_ttl(nodes[0], ttl_key, b"value", Duration::from_secs(60));
+
+        sim.run_for(Duration::from_secs(30));
+        assert_eq!(sim.get(nodes[9], ttl_key), Some(b"value".to_vec()));
+        sim.run_for(Duration::from_secs(90));
+        assert_eq!(sim.get(nodes[9], key), Some(b"value".to_vec()));
+        assert_eq!(sim.get(nodes[9], ttl_key), None);
+    }
+
+    #[test]
+    fn test_store_rejected_over_quota() {
+        let config = NodeConfig::builder()
+            .max_storage_entries(Some(1))
+            .eviction_policy(EvictionPolicy::Reject)
+            .build();
+        let (mut sim, bootstrap, _) = Simulation::with_nodes(5, 0, &NodeConfig::default());
+        let node = sim.add_node(Some(bootstrap), config);
+        let (key, other_key) = (Key::new([1; 32]), Key::new([2; 32]));
+
+        let confirmed = sim.insert(bootstrap, key, b"value");
+        assert!(confirmed.contains(&sim.node_data(node)));
+        let confirmed = sim.insert(bootstrap, other_key, b"value");
+        assert_eq!(confirmed, vec![sim.node_data(bootstrap)]);
+    }
+
+    #[test]
+    fn test_bootstrap_retries_seed_addrs() {
+        let mut sim = Simulation::new(7);
+        // the seed is the address of the second node, which does not exist yet
+        let config = NodeConfig::builder()
+            .bootstrap_addrs(vec!["10.0.0.2:8080".to_string()])
+            .build();
+        let node = sim.add_node(None, config);
+        assert!(!sim.is_bootstrapped(node));
+
+        let seed = sim.add_node(None, NodeConfig::default());
+        assert!(sim.is_bootstrapped(seed));
+        sim.run_for(Duration::from_secs(60));
+        assert!(sim.is_bootstrapped(node));
+        assert_eq!(sim.snapshot(node).nodes, vec![sim.node_data(seed)]);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::NodeConfig;
+use crate::error::{Error, Result};
+use crate::key::Key;
+use crate::node::core::{Event, NodeCore, OperationId};
+use crate::node::node_data::NodeData;
+use crate::protocol::{Message, Protocol, ProtocolStats};
+use crate::storage::StorageBackend;
+use crate::transport::Transport;
+use log::{info, warn};
+use rand::StdRng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A function that is called with the event of a completed operation.
+pub type Callback = Box<dyn FnOnce(Event) + Send>;
+
+/// An operation requested through an `EventLoopHandle`.
+pub enum Command {
+    Bootstrap(Option<NodeData>, Callback),
+    Restore(Vec<NodeData>, Callback),
+    Snapshot(Callback),
+    FindNode(Key, Callback),
+    Get(Key, Callback),
+    Insert(Key, Vec<u8>, Option<Duration>, usize, Callback),
+    StopRepublishing(Key),
+}
+
+/// An input to the event loop: either a message from the network or a command from a handle.
+pub enum Input {
+    Message(Message),
+    Command(Command),
+}
+
+impl From<Message> for Input {
+    fn from(message: Message) -> Self {
+        Input::Message(message)
+    }
+}
+
+impl Command {
+    /// Returns an error if the command inserts a value that is larger than `max_value_size`.
+    pub fn check_value_size(&self, max_value_size: usize) -> Result<()> {
+        match self {
+            Command::Insert(_, value, _, _, _) if value.len() > max_value_size => {
+                Err(Error::ValueTooLarge(value.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Constructs the `NodeCore` of a node at `addr`. If `id` is `None`, the id of the node is
+/// determined by `config`.
+///
+/// Returns an error if the identity file in `config` could not be read or written.
+pub fn new_core(
+    addr: String,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
+    storage: Box<dyn StorageBackend>,
+    id: Option<Key>,
+) -> Result<NodeCore> {
+    let mut rng = StdRng::new()?;
+    let id = match (id.or(config.id), &config.identity_file) {
+        (Some(id), _) => id,
+        (None, Some(identity_file)) => Key::load_or_create(identity_file, &mut rng)?,
+        (None, None) => Key::rand_with(&mut rng),
+    };
+    Ok(NodeCore::new(addr, id, config, clock, rng, storage))
+}
+
+/// The state of an event loop that does not depend on how it performs I/O: the `NodeCore`, and the
+/// callbacks of its pending operations.
+pub struct Driver {
+    pub core: NodeCore,
+    callbacks: HashMap<OperationId, Callback>,
+    is_bootstrapped: Arc<AtomicBool>,
+}
+
+impl Driver {
+    pub fn new(core: NodeCore, is_bootstrapped: Arc<AtomicBool>) -> Self {
+        Driver {
+            core,
+            callbacks: HashMap::new(),
+            is_bootstrapped,
+        }
+    }
+
+    pub fn handle_command(&mut self, command: Command) {
+        let (operation_id, callback) = match command {
+            Command::Bootstrap(bootstrap, callback) => (self.core.bootstrap(bootstrap), callback),
+            Command::Restore(nodes, callback) => (self.core.restore(nodes), callback),
+            Command::Snapshot(callback) => (self.core.snapshot(), callback),
+            Command::FindNode(key, callback) => (self.core.find_node(key), callback),
+            Command::Get(key, callback) => (self.core.get(key), callback),
+            Command::Insert(key, value, ttl, min_replicas, callback) => {
+                (self.core.insert(key, value, ttl, min_replicas), callback)
+            }
+            Command::StopRepublishing(key) => {
+                self.core.stop_republishing(&key);
+                return;
+            }
+        };
+        self.callbacks.insert(operation_id, callback);
+    }
+
+    /// Calls the callbacks of the completed operations of the `NodeCore`.
+    pub fn dispatch_events(&mut self) {
+        self.is_bootstrapped
+            .store(self.core.is_bootstrapped(), Ordering::Relaxed);
+        while let Some(event) = self.core.poll_event() {
+            let operation_id = match event {
+                Event::Bootstrapped(operation_id)
+                | Event::FoundNodes(operation_id, _)
+                | Event::FoundValue(operation_id, _)
+                | Event::Inserted(operation_id, _)
+                | Event::Snapshot(operation_id, _) => operation_id,
+            };
+            if let Some(callback) = self.callbacks.remove(&operation_id) {
+                callback(event);
+            }
+        }
+    }
+}
+
+/// A single thread that drives a `NodeCore`.
+///
+/// The event loop multiplexes received messages and commands over one channel, and waits on that
+/// channel until the next deadline of the `NodeCore`. Requests are not given threads of their own,
+/// so the number of threads used by a node does not depend on the number of requests.
+struct EventLoop {
+    driver: Driver,
+    protocol: Protocol,
+    clock: Arc<dyn Clock>,
+    rx: Receiver<Input>,
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        loop {
+            let timeout = (self.driver.core.next_deadline() - self.clock.now())
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            match self.rx.recv_timeout(timeout) {
+                Ok(Input::Message(Message::Kill)) => break,
+                Ok(Input::Message(message)) => self.driver.core.handle_message(message),
+                Ok(Input::Command(command)) => self.driver.handle_command(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.driver.core.handle_timeouts();
+            self.flush();
+        }
+        info!("{} - Killed event loop", self.driver.core.node_data().addr);
+    }
+
+    /// Sends the outgoing messages of the `NodeCore` and calls the callbacks of its completed
+    /// operations.
+    fn flush(&mut self) {
+        while let Some((message, dest)) = self.driver.core.poll_transmit() {
+            if let Err(err) = self.protocol.send_message(&message, &dest) {
+                warn!(
+                    "{} - Could not send message to {}: {}",
+                    self.driver.core.node_data().addr,
+                    dest.addr,
+                    err,
+                );
+            }
+        }
+        self.driver.dispatch_events();
+    }
+}
+
+/// A handle to a running event loop.
+#[derive(Clone)]
+pub struct EventLoopHandle {
+    tx: Sender<Input>,
+    node_data: Arc<NodeData>,
+    protocol: Protocol,
+    config: Arc<NodeConfig>,
+    is_bootstrapped: Arc<AtomicBool>,
+}
+
+impl EventLoopHandle {
+    /// Starts an event loop that communicates over `transport` with the protocol parameters in
+    /// `config`, and that stores its key-value pairs in `storage`. If `id` is `None`, the id of
+    /// the node is determined by `config`.
+    ///
+    /// Returns an error if the address of the transport could not be determined, or if the
+    /// identity file in `config` could not be read or written.
+    pub fn spawn<T>(
+        transport: T,
+        config: NodeConfig,
+        storage: Box<dyn StorageBackend>,
+        id: Option<Key>,
+    ) -> Result<Self>
+    where
+        T: Transport + 'static,
+    {
+        let addr = transport.local_addr()?.to_string();
+        let config = Arc::new(config);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let (tx, rx) = channel();
+        let core = new_core(addr, Arc::clone(&config), Arc::clone(&clock), storage, id)?;
+        let protocol = Protocol::new(
+            Arc::new(transport),
+            tx.clone(),
+            Arc::clone(&config),
+            Arc::clone(&clock),
+        );
+        let node_data = Arc::new(core.node_data().clone());
+        let is_bootstrapped = Arc::new(AtomicBool::new(false));
+
+        let event_loop = EventLoop {
+            driver: Driver::new(core, Arc::clone(&is_bootstrapped)),
+            protocol: protocol.clone(),
+            clock,
+            rx,
+        };
+        thread::spawn(move || event_loop.run());
+
+        Ok(EventLoopHandle {
+            tx,
+            node_data,
+            protocol,
+            config,
+            is_bootstrapped,
+        })
+    }
+
+    /// Sends `command` to the event loop. Returns an error if the event loop has stopped, or if
+    /// the value to insert is larger than `max_value_size`.
+    pub fn send(&self, command: Command) -> Result<()> {
+        command.check_value_size(self.config.max_value_size)?;
+        self.tx
+            .send(Input::Command(command))
+            .map_err(|_| Error::Shutdown)
+    }
+
+    /// Returns the `NodeData` associated with the node.
+    pub fn node_data(&self) -> &NodeData {
+        &self.node_data
+    }
+
+    /// Returns `true` once a bootstrap attempt received a response from at least one node, or
+    /// finished without any seeds.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.is_bootstrapped.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the counters describing the datagrams received by the node.
+    pub fn protocol_stats(&self) -> ProtocolStats {
+        self.protocol.stats()
+    }
+
+    /// Stops the event loop. Commands sent after the node was killed fail with
+    /// `Error::Shutdown`.
+    pub fn kill(&self) {
+        let _ = self.tx.send(Input::Message(Message::Kill));
+        if let Err(err) = self.protocol.kill() {
+            warn!("{} - Could not kill node: {}", self.node_data.addr, err);
+        }
+    }
+}
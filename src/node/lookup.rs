@@ -0,0 +1,234 @@
+use crate::config::NodeConfig;
+use crate::error::Result;
+use crate::key::Key;
+use crate::node::node_data::{NodeData, NodeDataDistancePair};
+use crate::protocol::{Response, ResponsePayload};
+use crate::KEY_LENGTH;
+use std::collections::{BinaryHeap, HashSet};
+
+/// The state of an iterative lookup of the closest nodes to a key.
+///
+/// `Lookup` only decides which nodes to query next; the caller is responsible for sending the
+/// `FIND_NODE` or `FIND_VALUE` RPCs returned by `next_queries`, and for passing each response or
+/// failure back through `handle_response`. This keeps the algorithm free of I/O and timers.
+///
+/// The search begins with the nodes from the routing table in its shortlist. It keeps at most
+/// `concurrency_param` RPCs in flight to the closest nodes not yet queried, and will continue to
+/// fill its shortlist until a response does not contain a closer node or until it runs out of
+/// nodes to query. Finally, it will query the remaining nodes in its shortlist until there are no
+/// remaining nodes or until it has found `replication_param` active nodes.
+pub struct Lookup {
+    origin: NodeData,
+    key: Key,
+    concurrency_param: usize,
+    replication_param: usize,
+    closest_distance: Key,
+    found_nodes: HashSet<NodeData>,
+    queried_nodes: HashSet<NodeData>,
+    queue: BinaryHeap<NodeDataDistancePair>,
+    in_flight: usize,
+    is_converging: bool,
+    is_finished: bool,
+    result: Option<ResponsePayload>,
+}
+
+impl Lookup {
+    /// Constructs a new `Lookup` for `key` started by `node_data` with an initial shortlist of
+    /// `closest_nodes`.
+    pub fn new(
+        node_data: &NodeData,
+        key: Key,
+        closest_nodes: Vec<NodeData>,
+        config: &NodeConfig,
+    ) -> Self {
+        let mut closest_distance = Key::new([255u8; KEY_LENGTH]);
+        for node_data in &closest_nodes {
+            closest_distance = closest_distance.min(key.xor(&node_data.id));
+        }
+
+        let mut found_nodes: HashSet<NodeData> = closest_nodes.iter().cloned().collect();
+        found_nodes.insert(node_data.clone());
+        let mut queried_nodes = HashSet::new();
+        queried_nodes.insert(node_data.clone());
+
+        let queue = closest_nodes
+            .into_iter()
+            .map(|node_data| {
+                let distance = node_data.id.xor(&key);
+                NodeDataDistancePair(node_data, distance)
+            })
+            .collect();
+
+        Lookup {
+            origin: node_data.clone(),
+            key,
+            concurrency_param: config.concurrency_param,
+            replication_param: config.replication_param,
+            closest_distance,
+            found_nodes,
+            queried_nodes,
+            queue,
+            in_flight: 0,
+            is_converging: true,
+            is_finished: false,
+            result: None,
+        }
+    }
+
+    /// Returns the nodes that should be queried next, and marks them as in flight. If the lookup
+    /// has finished, the result becomes available through `take_result`.
+    pub fn next_queries(&mut self) -> Vec<NodeData> {
+        let mut ret = Vec::new();
+        if self.is_finished {
+            return ret;
+        }
+
+        if !self.is_converging && self.queried_nodes.len() >= self.replication_param {
+            self.finish();
+            return ret;
+        }
+
+        while self.in_flight < self.concurrency_param {
+            match self.queue.pop() {
+                Some(NodeDataDistancePair(node_data, _)) => {
+                    ret.push(node_data);
+                    self.in_flight += 1;
+                }
+                None => break,
+            }
+        }
+
+        if self.in_flight == 0 {
+            self.finish();
+        }
+        ret
+    }
+
+    /// Handles the response to, or the failure of, a previously returned query.
+    pub fn handle_response(&mut self, response: Result<Response>) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if self.is_finished {
+            return;
+        }
+
+        match response {
+            Ok(Response {
+                payload: ResponsePayload::Nodes(nodes),
+                receiver,
+                ..
+            }) => {
+                self.queried_nodes.insert(receiver);
+                let mut found_closer = false;
+                for node_data in nodes {
+                    if self.found_nodes.contains(&node_data) {
+                        continue;
+                    }
+                    let distance = node_data.id.xor(&self.key);
+                    if distance < self.closest_distance {
+                        self.closest_distance = distance;
+                        found_closer = true;
+                    }
+                    self.found_nodes.insert(node_data.clone());
+                    self.queue.push(NodeDataDistancePair(node_data, distance));
+                }
+                if !found_closer {
+                    self.is_converging = false;
+                }
+            }
+            Ok(Response {
+                payload: ResponsePayload::Value(value, remaining),
+                ..
+            }) => {
+                self.is_finished = true;
+                self.result = Some(ResponsePayload::Value(value, remaining));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the closest node to the key that responded with nodes instead of the value, and the
+    /// number of found nodes that are closer to the key than it. Returns `None` if no node other
+    /// than the origin of the lookup responded with nodes.
+    pub fn cache_target(&self) -> Option<(NodeData, usize)> {
+        let key = self.key;
+        let target = self
+            .queried_nodes
+            .iter()
+            .filter(|node_data| **node_data != self.origin)
+            .min_by_key(|node_data| node_data.id.xor(&key))?;
+        let distance = target.id.xor(&key);
+        let closer_nodes = self
+            .found_nodes
+            .iter()
+            .filter(|node_data| **node_data != self.origin && node_data.id.xor(&key) < distance)
+            .count();
+        Some((target.clone(), closer_nodes))
+    }
+
+    /// Returns the result of the lookup if it has finished. The result is either the value
+    /// associated with the key, or the closest `replication_param` nodes that responded.
+    pub fn take_result(&mut self) -> Option<ResponsePayload> {
+        self.result.take()
+    }
+
+    fn finish(&mut self) {
+        let key = self.key;
+        let mut ret: Vec<NodeData> = self.queried_nodes.iter().cloned().collect();
+        ret.sort_by_key(|node_data| node_data.id.xor(&key));
+        ret.truncate(self.replication_param);
+        self.is_finished = true;
+        self.result = Some(ResponsePayload::Nodes(ret));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lookup;
+    use crate::config::NodeConfig;
+    use crate::key::Key;
+    use crate::node::node_data::NodeData;
+    use crate::protocol::{Request, RequestPayload, Response, ResponsePayload};
+    use std::time::Duration;
+
+    fn node_data(byte: u8) -> NodeData {
+        NodeData {
+            addr: format!("127.0.0.1:{}", 8000 + u16::from(byte)),
+            id: Key::new([byte; 32]),
+        }
+    }
+
+    fn response(origin: &NodeData, receiver: NodeData, payload: ResponsePayload) -> Response {
+        Response {
+            request: Request {
+                id: Key::new([0; 32]),
+                sender: origin.clone(),
+                payload: RequestPayload::FindValue(Key::new([0; 32])),
+            },
+            receiver,
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_cache_target_is_closest_node_without_value() {
+        let origin = node_data(255);
+        let key = Key::new([0; 32]);
+        let initial_nodes = vec![node_data(3), node_data(2)];
+        let mut lookup = Lookup::new(&origin, key, initial_nodes, &NodeConfig::default());
+        assert_eq!(lookup.next_queries().len(), 2);
+
+        let nodes = ResponsePayload::Nodes(vec![node_data(1)]);
+        lookup.handle_response(Ok(response(&origin, node_data(3), nodes)));
+        let nodes = ResponsePayload::Nodes(Vec::new());
+        lookup.handle_response(Ok(response(&origin, node_data(2), nodes)));
+        assert_eq!(lookup.next_queries(), vec![node_data(1)]);
+
+        let value = ResponsePayload::Value(b"value".to_vec(), Duration::from_secs(60));
+        lookup.handle_response(Ok(response(&origin, node_data(1), value)));
+        match lookup.take_result() {
+            Some(ResponsePayload::Value(value, _)) => assert_eq!(value, b"value"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(lookup.cache_target(), Some((node_data(2), 1)));
+    }
+}
@@ -1,490 +1,500 @@
+#[cfg(feature = "async")]
+pub mod async_node;
+pub(crate) mod core;
+mod event_loop;
+mod lookup;
 pub mod node_data;
 
+use crate::config::NodeConfig;
+use crate::error::{Error, Result};
 use crate::key::Key;
-use crate::node::node_data::{NodeData, NodeDataDistancePair};
-use crate::protocol::{Message, Protocol, Request, RequestPayload, Response, ResponsePayload};
-use crate::routing::RoutingTable;
-use crate::storage::Storage;
-use crate::{
-    BUCKET_REFRESH_INTERVAL, CONCURRENCY_PARAM, KEY_LENGTH, REPLICATION_PARAM, REQUEST_TIMEOUT,
-};
-use log::{debug, info, log, warn};
-use std::cmp;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use crate::node::core::Event;
+use crate::node::event_loop::{Callback, Command, EventLoopHandle};
+use crate::node::node_data::NodeData;
+use crate::protocol::ProtocolStats;
+use crate::routing::RoutingSnapshot;
+use crate::storage::{MemoryStorage, StorageBackend};
+use crate::transport::Transport;
 use std::net::UdpSocket;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::mpsc::channel;
 use std::time::Duration;
 
+/// Returns `confirmed` if it contains at least `min_replicas` nodes.
+fn check_replicas(confirmed: Vec<NodeData>, min_replicas: usize) -> Result<Vec<NodeData>> {
+    if confirmed.len() < min_replicas {
+        Err(Error::InsufficientReplicas {
+            required: min_replicas,
+            confirmed,
+        })
+    } else {
+        Ok(confirmed)
+    }
+}
+
 /// A node in the Kademlia DHT.
+///
+/// All of the node's requests, responses and timers are handled by a single event loop thread, so
+/// the number of threads used by a node stays bounded regardless of the request rate. The methods
+/// of `Node` block until the event loop completes the operation.
 #[derive(Clone)]
 pub struct Node {
-    node_data: Arc<NodeData>,
-    routing_table: Arc<Mutex<RoutingTable>>,
-    storage: Arc<Mutex<Storage>>,
-    pending_requests: Arc<Mutex<HashMap<Key, Sender<Response>>>>,
-    protocol: Arc<Protocol>,
-    is_active: Arc<AtomicBool>,
+    handle: EventLoopHandle,
 }
 
 impl Node {
     /// Constructs a new `Node` on a specific ip and port, and bootstraps the node with an existing
-    /// node if `bootstrap` is not `None`.
-    pub fn new(ip: &str, port: &str, bootstrap: Option<NodeData>) -> Self {
+    /// node if `bootstrap` is not `None`. The node uses the default `NodeConfig`.
+    ///
+    /// Returns an error if the node could not bind to the address, or if the identity file in
+    /// `config` could not be read or written.
+    pub fn new(ip: &str, port: &str, bootstrap: Option<NodeData>) -> Result<Self> {
+        Node::with_config(ip, port, bootstrap, NodeConfig::default())
+    }
+
+    /// Constructs a new `Node` on a specific ip and port with the protocol parameters in `config`,
+    /// and bootstraps the node with an existing node if `bootstrap` is not `None`.
+    ///
+    /// Returns an error if the node could not bind to the address, or if the identity file in
+    /// `config` could not be read or written.
+    pub fn with_config(
+        ip: &str,
+        port: &str,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+    ) -> Result<Self> {
         let addr = format!("{}:{}", ip, port);
-        let socket = UdpSocket::bind(addr).expect("Error: could not bind to address.");
-        let node_data = Arc::new(NodeData {
-            addr: socket.local_addr().unwrap().to_string(),
-            id: Key::rand(),
-        });
-        let mut routing_table = RoutingTable::new(Arc::clone(&node_data));
-        let (message_tx, message_rx) = channel();
-        let protocol = Protocol::new(socket, message_tx);
+        let socket = UdpSocket::bind(addr).map_err(Error::Bind)?;
+        Node::with_transport(socket, bootstrap, config)
+    }
 
-        // directly use update_node as update_routing_table is async
-        if let Some(bootstrap_data) = bootstrap {
-            routing_table.update_node(bootstrap_data);
-        }
+    /// Constructs a new `Node` that communicates over `transport` with the protocol
+    /// parameters in `config`, and bootstraps the node with an existing node if `bootstrap` is not
+    /// `None`. The node stores its key-value pairs in a `MemoryStorage`.
+    ///
+    /// Returns an error if the address of the transport could not be determined, or if the
+    /// identity file in `config` could not be read or written.
+    pub fn with_transport<T>(
+        transport: T,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+    ) -> Result<Self>
+    where
+        T: Transport + 'static,
+    {
+        Node::with_storage(transport, bootstrap, config, MemoryStorage::new())
+    }
 
-        let mut ret = Node {
-            node_data,
-            routing_table: Arc::new(Mutex::new(routing_table)),
-            storage: Arc::new(Mutex::new(Storage::new())),
-            pending_requests: Arc::new(Mutex::new(HashMap::new())),
-            protocol: Arc::new(protocol),
-            is_active: Arc::new(AtomicBool::new(true)),
+    /// Constructs a new `Node` that communicates over `transport` with the protocol
+    /// parameters in `config` and stores its key-value pairs in `storage`, and bootstraps the node
+    /// with an existing node if `bootstrap` is not `None`.
+    ///
+    /// Returns an error if the address of the transport could not be determined, or if the
+    /// identity file in `config` could not be read or written.
+    pub fn with_storage<T, S>(
+        transport: T,
+        bootstrap: Option<NodeData>,
+        config: NodeConfig,
+        storage: S,
+    ) -> Result<Self>
+    where
+        T: Transport + 'static,
+        S: StorageBackend + 'static,
+    {
+        let node = Node {
+            handle: EventLoopHandle::spawn(transport, config, Box::new(storage), None)?,
         };
+        node.call(|callback| Command::Bootstrap(bootstrap, callback))?;
+        Ok(node)
+    }
 
-        ret.start_message_handler(message_rx);
-        ret.start_bucket_refresher();
-        ret.bootstrap_routing_table();
-        ret
+    /// Constructs a new `Node` with the id in `snapshot`, rather than the id in `config`, that
+    /// communicates over `transport` with the protocol parameters in `config` and stores its
+    /// key-value pairs in `storage`. The node bootstraps from the contacts in `snapshot`, each of
+    /// which is pinged so that the contacts that no longer respond are removed from the routing
+    /// table.
+    ///
+    /// Returns an error if the address of the transport could not be determined.
+    pub fn from_snapshot<T, S>(
+        transport: T,
+        snapshot: RoutingSnapshot,
+        config: NodeConfig,
+        storage: S,
+    ) -> Result<Self>
+    where
+        T: Transport + 'static,
+        S: StorageBackend + 'static,
+    {
+        let handle =
+            EventLoopHandle::spawn(transport, config, Box::new(storage), Some(snapshot.id))?;
+        let node = Node { handle };
+        node.call(|callback| Command::Restore(snapshot.nodes, callback))?;
+        Ok(node)
     }
 
-    /// Starts a thread that listens to responses.
-    fn start_message_handler(&self, rx: Receiver<Message>) {
-        let mut node = self.clone();
-        thread::spawn(move || {
-            for request in rx.iter() {
-                match request {
-                    Message::Request(request) => node.handle_request(&request),
-                    Message::Response(response) => node.handle_response(&response),
-                    Message::Kill => {
-                        node.is_active.store(false, Ordering::Release);
-                        info!("{} - Killed message handler", node.node_data.addr);
-                        break;
-                    }
-                }
-            }
+    /// Sends the command built by `command` to the event loop and blocks until the operation
+    /// completes. Returns an error if the event loop stopped before the operation completed.
+    fn call<F>(&self, command: F) -> Result<Event>
+    where
+        F: FnOnce(Callback) -> Command,
+    {
+        let (tx, rx) = channel();
+        let callback: Callback = Box::new(move |event| {
+            let _ = tx.send(event);
         });
+        self.handle.send(command(callback))?;
+        rx.recv().map_err(|_| Error::Shutdown)
     }
 
-    /// Starts a thread that refreshes stale routing buckets.
-    fn start_bucket_refresher(&self) {
-        let mut node = self.clone();
-        thread::spawn(move || {
-            thread::sleep(Duration::from_secs(BUCKET_REFRESH_INTERVAL));
-            while node.is_active.load(Ordering::Acquire) {
-                let stale_indexes = {
-                    let routing_table = match node.routing_table.lock() {
-                        Ok(routing_table) => routing_table,
-                        Err(poisoned) => poisoned.into_inner(),
-                    };
-                    routing_table.get_stale_indexes()
-                };
-
-                for index in stale_indexes {
-                    node.lookup_nodes(&Key::rand_in_range(index), true);
-                }
-                thread::sleep(Duration::from_secs(BUCKET_REFRESH_INTERVAL));
-            }
-            warn!("{} - Killed bucket refresher", node.node_data.addr);
-        });
+    /// Inserts a key-value pair into the DHT, and blocks until the closest nodes to the key have
+    /// acknowledged or failed to acknowledge the `STORE` RPC. Returns the nodes that acknowledged
+    /// it, ordered by distance to the key. The node republishes the pair every
+    /// `republish_interval` until `stop_republishing` is called.
+    ///
+    /// Returns an error if the node has been shut down or if the value is larger than
+    /// `max_value_size`.
+    pub fn insert(&mut self, key: Key, value: &[u8]) -> Result<Vec<NodeData>> {
+        self.insert_with_min_replicas(key, value, 0)
     }
 
-    /// Bootstraps the routing table using an existing node. The node first looks up its id to
-    /// identify the closest nodes to it. Then it refreshes all routing buckets by looking up a
-    /// random key in the buckets' range.
-    fn bootstrap_routing_table(&mut self) {
-        let target_key = self.node_data.id;
-        self.lookup_nodes(&target_key, true);
+    /// Inserts a key-value pair into the DHT like `insert`, but returns
+    /// `Error::InsufficientReplicas` if fewer than `min_replicas` nodes acknowledged the `STORE`
+    /// RPC.
+    pub fn insert_with_min_replicas(
+        &mut self,
+        key: Key,
+        value: &[u8],
+        min_replicas: usize,
+    ) -> Result<Vec<NodeData>> {
+        self.insert_item(key, value, None, min_replicas)
+    }
 
-        let bucket_size = { self.routing_table.lock().unwrap().size() };
+    /// Inserts a key-value pair into the DHT like `insert`, but the pair expires after `ttl`
+    /// instead of after the receivers' `key_expiration`. The time-to-live is capped by the
+    /// receivers' `max_ttl`, and the node stops republishing the pair once it has elapsed.
+    pub fn insert_with_ttl(
+        &mut self,
+        key: Key,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<Vec<NodeData>> {
+        self.insert_item(key, value, Some(ttl), 0)
+    }
 
-        for i in 0..bucket_size {
-            self.lookup_nodes(&Key::rand_in_range(i), true);
+    fn insert_item(
+        &mut self,
+        key: Key,
+        value: &[u8],
+        ttl: Option<Duration>,
+        min_replicas: usize,
+    ) -> Result<Vec<NodeData>> {
+        match self
+            .call(|callback| Command::Insert(key, value.to_vec(), ttl, min_replicas, callback))?
+        {
+            Event::Inserted(_, confirmed) => check_replicas(confirmed, min_replicas),
+            event => unreachable!("Unexpected event for an insert: {:?}", event),
         }
     }
 
-    /// Upserts the routing table. If the node cannot be inserted into the routing table, it
-    /// removes and pings the least recently seen node. If the least recently seen node responds,
-    /// it will be readded into the routing table, and the current node will be ignored.
-    fn update_routing_table(&mut self, node_data: NodeData) {
-        debug!("{} updating {}", self.node_data.addr, node_data.addr);
-        let mut node = self.clone();
-        thread::spawn(move || {
-            let lrs_node_opt = {
-                let mut routing_table = match node.routing_table.lock() {
-                    Ok(routing_table) => routing_table,
-                    Err(poisoned) => poisoned.into_inner(),
-                };
-                if !routing_table.update_node(node_data.clone()) {
-                    routing_table.remove_lrs(&node_data.id)
-                } else {
-                    None
-                }
-            };
-
-            // Ping the lrs node and move to front of bucket if active
-            if let Some(lrs_node) = lrs_node_opt {
-                node.rpc_ping(&lrs_node);
-                let mut routing_table = match node.routing_table.lock() {
-                    Ok(routing_table) => routing_table,
-                    Err(poisoned) => poisoned.into_inner(),
-                };
-                routing_table.update_node(node_data);
-            }
-        });
+    /// Stops republishing the key-value pair with `key` that was inserted by this node. The nodes
+    /// that store the pair keep replicating it until its time-to-live elapses.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub fn stop_republishing(&mut self, key: &Key) -> Result<()> {
+        self.handle.send(Command::StopRepublishing(*key))
     }
 
-    /// Handles a request RPC.
-    fn handle_request(&mut self, request: &Request) {
-        info!(
-            "{} - Receiving request from {} {:#?}",
-            self.node_data.addr, request.sender.addr, request.payload,
-        );
-        self.clone().update_routing_table(request.sender.clone());
-        let receiver = (*self.node_data).clone();
-        let payload = match request.payload.clone() {
-            RequestPayload::Ping => ResponsePayload::Pong,
-            RequestPayload::Store(key, value) => {
-                self.storage.lock().unwrap().insert(key, value);
-                ResponsePayload::Pong
-            }
-            RequestPayload::FindNode(key) => ResponsePayload::Nodes(
-                self.routing_table
-                    .lock()
-                    .unwrap()
-                    .get_closest_nodes(&key, REPLICATION_PARAM),
-            ),
-            RequestPayload::FindValue(key) => {
-                if let Some(value) = self.storage.lock().unwrap().get(&key) {
-                    ResponsePayload::Value(value.clone())
-                } else {
-                    ResponsePayload::Nodes(
-                        self.routing_table
-                            .lock()
-                            .unwrap()
-                            .get_closest_nodes(&key, REPLICATION_PARAM),
-                    )
-                }
-            }
-        };
-
-        self.protocol.send_message(
-            &Message::Response(Response {
-                request: request.clone(),
-                receiver,
-                payload,
-            }),
-            &request.sender,
-        )
+    /// Inserts a key-value pair with a string value into the DHT. The value is stored as its UTF-8
+    /// bytes. Returns the nodes that acknowledged the `STORE` RPC.
+    ///
+    /// Returns an error if the node has been shut down or if the value is larger than
+    /// `max_value_size`.
+    pub fn insert_str(&mut self, key: Key, value: &str) -> Result<Vec<NodeData>> {
+        self.insert(key, value.as_bytes())
     }
 
-    /// Handles a response RPC. If the id in the response does not match any outgoing request, then
-    /// the response will be ignored.
-    fn handle_response(&mut self, response: &Response) {
-        self.clone().update_routing_table(response.receiver.clone());
-        let pending_requests = self.pending_requests.lock().unwrap();
-        let Response { ref request, .. } = response.clone();
-        if let Some(sender) = pending_requests.get(&request.id) {
-            info!(
-                "{} - Receiving response from {} {:#?}",
-                self.node_data.addr, response.receiver.addr, response.payload,
-            );
-            sender.send(response.clone()).unwrap();
-        } else {
-            warn!(
-                "{} - Original request not found; irrelevant response or expired request.",
-                self.node_data.addr
-            );
+    /// Gets the value associated with a particular key in the DHT. Returns `None` if the key was
+    /// not found.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub fn get(&mut self, key: &Key) -> Result<Option<Vec<u8>>> {
+        match self.call(|callback| Command::Get(*key, callback))? {
+            Event::FoundValue(_, value) => Ok(value),
+            event => unreachable!("Unexpected event for a get: {:?}", event),
         }
     }
 
-    /// Sends a request RPC.
-    fn send_request(&mut self, dest: &NodeData, payload: RequestPayload) -> Option<Response> {
-        info!(
-            "{} - Sending request to {} {:#?}",
-            self.node_data.addr, dest.addr, payload
-        );
-        let (response_tx, response_rx) = channel();
-        let mut pending_requests = self.pending_requests.lock().unwrap();
-        let mut token = Key::rand();
-
-        while pending_requests.contains_key(&token) {
-            token = Key::rand();
+    /// Gets the value associated with a particular key in the DHT as a string. Returns `None` if
+    /// the key was not found.
+    ///
+    /// Returns an error if the node has been shut down or if the value is not valid UTF-8.
+    pub fn get_str(&mut self, key: &Key) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
         }
-        pending_requests.insert(token, response_tx);
-        drop(pending_requests);
-
-        self.protocol.send_message(
-            &Message::Request(Request {
-                id: token,
-                sender: (*self.node_data).clone(),
-                payload,
-            }),
-            dest,
-        );
+    }
 
-        match response_rx.recv_timeout(Duration::from_millis(REQUEST_TIMEOUT)) {
-            Ok(response) => {
-                let mut pending_requests = self.pending_requests.lock().unwrap();
-                pending_requests.remove(&token);
-                Some(response)
-            }
-            Err(_) => {
-                warn!(
-                    "{} - Request to {} timed out after waiting for {} milliseconds",
-                    self.node_data.addr, dest.addr, REQUEST_TIMEOUT
-                );
-                let mut pending_requests = self.pending_requests.lock().unwrap();
-                pending_requests.remove(&token);
-                let mut routing_table = self.routing_table.lock().unwrap();
-                routing_table.remove_node(dest);
-                None
-            }
+    /// Returns the closest nodes to `key` in the DHT.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub fn find_node(&mut self, key: &Key) -> Result<Vec<NodeData>> {
+        match self.call(|callback| Command::FindNode(*key, callback))? {
+            Event::FoundNodes(_, nodes) => Ok(nodes),
+            event => unreachable!("Unexpected event for a node lookup: {:?}", event),
         }
     }
 
-    /// Sends a `PING` RPC.
-    fn rpc_ping(&mut self, dest: &NodeData) -> Option<Response> {
-        self.send_request(dest, RequestPayload::Ping)
+    /// Returns a snapshot of the node's id and routing table, which can be used to restart the
+    /// node with `Node::from_snapshot`.
+    ///
+    /// Returns an error if the node has been shut down.
+    pub fn snapshot(&mut self) -> Result<RoutingSnapshot> {
+        match self.call(Command::Snapshot)? {
+            Event::Snapshot(_, snapshot) => Ok(snapshot),
+            event => unreachable!("Unexpected event for a snapshot: {:?}", event),
+        }
     }
 
-    /// Sends a `STORE` RPC.
-    fn rpc_store(&mut self, dest: &NodeData, key: Key, value: String) -> Option<Response> {
-        self.send_request(dest, RequestPayload::Store(key, value))
+    /// Returns the `NodeData` associated with the node.
+    pub fn node_data(&self) -> NodeData {
+        self.handle.node_data().clone()
     }
 
-    /// Sends a `FIND_NODE` RPC.
-    fn rpc_find_node(&mut self, dest: &NodeData, key: &Key) -> Option<Response> {
-        self.send_request(dest, RequestPayload::FindNode(*key))
+    /// Returns `true` once a bootstrap attempt received a response from at least one node, or
+    /// finished without any seeds. A node that could not reach any seed keeps
+    /// retrying in the background with exponential backoff.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.handle.is_bootstrapped()
     }
 
-    /// Sends a `FIND_VALUE` RPC.
-    fn rpc_find_value(&mut self, dest: &NodeData, key: &Key) -> Option<Response> {
-        self.send_request(dest, RequestPayload::FindValue(*key))
+    /// Returns a snapshot of the counters describing the datagrams received by the node.
+    pub fn protocol_stats(&self) -> ProtocolStats {
+        self.handle.protocol_stats()
     }
 
-    /// Spawns a thread that sends either a `FIND_NODE` or a `FIND_VALUE` RPC.
-    fn spawn_find_rpc(
-        mut self,
-        dest: NodeData,
-        key: Key,
-        sender: Sender<Option<Response>>,
-        find_node: bool,
-    ) {
-        thread::spawn(move || {
-            let find_err = {
-                if find_node {
-                    sender.send(self.rpc_find_node(&dest, &key)).is_err()
-                } else {
-                    sender.send(self.rpc_find_value(&dest, &key)).is_err()
-                }
-            };
-
-            if find_err {
-                warn!("Receiver closed channel before rpc returned.");
-            }
-        });
+    /// Kills the current node and all active threads.
+    pub fn kill(&self) {
+        self.handle.kill();
     }
+}
 
-    /// Iteratively looks up nodes to determine the closest nodes to `key`. The search begins by
-    /// selecting `CONCURRENCY_PARAM` nodes in the routing table and adding it to a shortlist. It
-    /// then sends out either `FIND_NODE` or `FIND_VALUE` RPCs to `CONCURRENCY_PARAM` nodes not yet
-    /// queried in the shortlist. The node will continue to fill its shortlist until it did not find
-    /// a closer node for a round of RPCs or if runs out of nodes to query. Finally, it will query
-    /// the remaining nodes in its shortlist until there are no remaining nodes or if it has found
-    /// `REPLICATION_PARAM` active nodes.
-    fn lookup_nodes(&mut self, key: &Key, find_node: bool) -> ResponsePayload {
-        let routing_table = self.routing_table.lock().unwrap();
-        let closest_nodes = routing_table.get_closest_nodes(key, CONCURRENCY_PARAM);
-        drop(routing_table);
-
-        let mut closest_distance = Key::new([255u8; KEY_LENGTH]);
-        for node_data in &closest_nodes {
-            closest_distance = cmp::min(closest_distance, key.xor(&node_data.id))
+#[cfg(test)]
+mod tests {
+    use super::Node;
+    use crate::config::NodeConfig;
+    use crate::error::{Error, Result};
+    use crate::key::Key;
+    use crate::storage::{MemoryStorage, StorageBackend, StorageEntry, StorageStats};
+    use crate::transport::MemoryNetwork;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A `StorageBackend` whose contents can be inspected while a node owns it.
+    #[derive(Clone, Default)]
+    struct SharedStorage(Arc<Mutex<MemoryStorage>>);
+
+    impl StorageBackend for SharedStorage {
+        fn insert(&mut self, key: Key, value: Vec<u8>, ttl: Duration) -> Result<()> {
+            self.0.lock().unwrap().insert(key, value, ttl)
         }
 
-        // initialize found nodes, queried nodes, and priority queue
-        let mut found_nodes: HashSet<NodeData> = closest_nodes.clone().into_iter().collect();
-        found_nodes.insert((*self.node_data).clone());
-        let mut queried_nodes = HashSet::new();
-        queried_nodes.insert((*self.node_data).clone());
-
-        let mut queue: BinaryHeap<NodeDataDistancePair> = BinaryHeap::from(
-            closest_nodes
-                .into_iter()
-                .map(|node_data| NodeDataDistancePair(node_data.clone(), node_data.id.xor(key)))
-                .collect::<Vec<NodeDataDistancePair>>(),
-        );
+        fn get(&mut self, key: &Key) -> Option<(Vec<u8>, Duration)> {
+            self.0.lock().unwrap().get(key)
+        }
 
-        let (tx, rx) = channel();
+        fn remove(&mut self, key: &Key) -> Result<Option<Vec<u8>>> {
+            self.0.lock().unwrap().remove(key)
+        }
 
-        let mut concurrent_thread_count = 0;
-
-        // spawn initial find requests
-        for _ in 0..CONCURRENCY_PARAM {
-            if !queue.is_empty() {
-                self.clone().spawn_find_rpc(
-                    queue.pop().unwrap().0,
-                    key.clone(),
-                    tx.clone(),
-                    find_node,
-                );
-                concurrent_thread_count += 1;
-            }
+        fn iter(&mut self) -> Box<dyn Iterator<Item = StorageEntry> + '_> {
+            let entries: Vec<_> = self.0.lock().unwrap().iter().collect();
+            Box::new(entries.into_iter())
         }
 
-        // loop until we could not find a closer node for a round or if no threads are running
-        while concurrent_thread_count > 0 {
-            while concurrent_thread_count < CONCURRENCY_PARAM && !queue.is_empty() {
-                self.clone().spawn_find_rpc(
-                    queue.pop().unwrap().0,
-                    key.clone(),
-                    tx.clone(),
-                    find_node,
-                );
-                concurrent_thread_count += 1;
-            }
+        fn expire(&mut self) -> usize {
+            self.0.lock().unwrap().expire()
+        }
 
-            let mut is_terminated = true;
-            let response_opt = rx.recv().unwrap();
-            concurrent_thread_count -= 1;
-
-            match response_opt {
-                Some(Response {
-                    payload: ResponsePayload::Nodes(nodes),
-                    receiver,
-                    ..
-                }) => {
-                    queried_nodes.insert(receiver);
-                    for node_data in nodes {
-                        let curr_distance = node_data.id.xor(key);
-
-                        if !found_nodes.contains(&node_data) {
-                            if curr_distance < closest_distance {
-                                closest_distance = curr_distance;
-                                is_terminated = false;
-                            }
-
-                            found_nodes.insert(node_data.clone());
-                            let dist = node_data.id.xor(key);
-                            let next = NodeDataDistancePair(node_data.clone(), dist);
-                            queue.push(next.clone());
-                        }
-                    }
-                }
-                Some(Response {
-                    payload: ResponsePayload::Value(value),
-                    ..
-                }) => return ResponsePayload::Value(value),
-                _ => is_terminated = false,
-            }
+        fn stats(&self) -> StorageStats {
+            self.0.lock().unwrap().stats()
+        }
+    }
 
-            if is_terminated {
-                break;
-            }
-            debug!("CURRENT CLOSEST DISTANCE IS {:?}", closest_distance);
+    #[test]
+    fn test_insert_get_memory_transport() {
+        let network = MemoryNetwork::new();
+        let bootstrap = Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        let mut nodes = vec![bootstrap.clone()];
+        for _ in 0..20 {
+            let node = Node::with_transport(
+                network.bind(),
+                Some(bootstrap.node_data()),
+                NodeConfig::default(),
+            )
+            .unwrap();
+            nodes.push(node);
         }
 
-        debug!(
-            "{} TERMINATED LOOKUP BECAUSE NOT CLOSER OR NO THREADS WITH DISTANCE {:?}",
-            self.node_data.addr, closest_distance,
-        );
+        assert!(nodes.iter().all(Node::is_bootstrapped));
 
-        // loop until no threads are running or if we found REPLICATION_PARAM active nodes
-        while queried_nodes.len() < REPLICATION_PARAM {
-            while concurrent_thread_count < CONCURRENCY_PARAM && !queue.is_empty() {
-                self.clone().spawn_find_rpc(
-                    queue.pop().unwrap().0,
-                    key.clone(),
-                    tx.clone(),
-                    find_node,
-                );
-                concurrent_thread_count += 1;
-            }
-            if concurrent_thread_count == 0 {
-                break;
-            }
+        let key = Key::rand();
+        let confirmed = nodes[1].insert(key, b"value").unwrap();
+        assert_eq!(confirmed.len(), NodeConfig::default().replication_param);
 
-            let response_opt = rx.recv().unwrap();
-            concurrent_thread_count -= 1;
-
-            match response_opt {
-                Some(Response {
-                    payload: ResponsePayload::Nodes(nodes),
-                    receiver,
-                    ..
-                }) => {
-                    queried_nodes.insert(receiver);
-                    for node_data in nodes {
-                        if !found_nodes.contains(&node_data) {
-                            found_nodes.insert(node_data.clone());
-                            let dist = node_data.id.xor(key);
-                            let next = NodeDataDistancePair(node_data.clone(), dist);
-                            queue.push(next.clone());
-                        }
-                    }
-                }
-                Some(Response {
-                    payload: ResponsePayload::Value(value),
-                    ..
-                }) => return ResponsePayload::Value(value),
-                _ => {}
-            }
+        assert_eq!(nodes[20].get(&key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(nodes[20].get_str(&key).unwrap(), Some("value".to_string()));
+
+        let target = nodes[7].node_data();
+        let closest = nodes[13].find_node(&target.id).unwrap();
+        assert_eq!(closest.first(), Some(&target));
+    }
+
+    #[test]
+    fn test_insert_get_large_value() {
+        let network = MemoryNetwork::new();
+        let config = NodeConfig::builder().max_value_size(100_000).build();
+        let bootstrap = Node::with_transport(network.bind(), None, config.clone()).unwrap();
+        let mut node =
+            Node::with_transport(network.bind(), Some(bootstrap.node_data()), config).unwrap();
+
+        let key = Key::rand();
+        let value: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        node.insert(key, &value).unwrap();
+        match node.insert(key, &[0; 100_001]) {
+            Err(Error::ValueTooLarge(100_001)) => {}
+            other => panic!("unexpected result {:?}", other),
         }
 
-        let mut ret: Vec<NodeData> = queried_nodes.into_iter().collect();
-        ret.sort_by_key(|node_data| node_data.id.xor(key));
-        ret.truncate(REPLICATION_PARAM);
-        debug!("{} -  CLOSEST NODES ARE {:#?}", self.node_data.addr, ret);
-        ResponsePayload::Nodes(ret)
+        let mut bootstrap = bootstrap;
+        assert_eq!(bootstrap.get(&key).unwrap(), Some(value));
     }
 
-    /// Inserts a key-value pair into the DHT.
-    pub fn insert(&mut self, key: Key, value: &str) {
-        if let ResponsePayload::Nodes(nodes) = self.lookup_nodes(&key, true) {
-            for dest in nodes {
-                let mut node = self.clone();
-                let key_clone = key;
-                let value_clone = value.to_string();
-                thread::spawn(move || {
-                    node.rpc_store(&dest, key_clone, value_clone);
-                });
-            }
+    #[test]
+    fn test_binary_values() {
+        let network = MemoryNetwork::new();
+        let mut bootstrap =
+            Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        let mut node = Node::with_transport(
+            network.bind(),
+            Some(bootstrap.node_data()),
+            NodeConfig::default(),
+        )
+        .unwrap();
+
+        // values that are not valid UTF-8 are stored as is
+        let (key, str_key) = (Key::new([1; 32]), Key::new([2; 32]));
+        let value = vec![0xFF, 0x00, 0xFE, 0x80];
+        node.insert(key, &value).unwrap();
+        assert_eq!(bootstrap.get(&key).unwrap(), Some(value));
+        match bootstrap.get_str(&key) {
+            Err(Error::Utf8(_)) => {}
+            other => panic!("unexpected result {:?}", other),
         }
+
+        node.insert_str(str_key, "värde").unwrap();
+        assert_eq!(
+            bootstrap.get_str(&str_key).unwrap(),
+            Some("värde".to_string())
+        );
+        assert_eq!(bootstrap.get_str(&Key::new([3; 32])).unwrap(), None);
     }
 
-    /// Gets the value associated with a particular key in the DHT. Returns `None` if the key was
-    /// not found.
-    pub fn get(&mut self, key: &Key) -> Option<String> {
-        if let ResponsePayload::Value(value) = self.lookup_nodes(key, false) {
-            Some(value)
-        } else {
-            None
+    #[test]
+    fn test_insert_with_min_replicas() {
+        let network = MemoryNetwork::new();
+        let mut bootstrap =
+            Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        let node = Node::with_transport(
+            network.bind(),
+            Some(bootstrap.node_data()),
+            NodeConfig::default(),
+        )
+        .unwrap();
+
+        let key = Key::rand();
+        let confirmed = bootstrap
+            .insert_with_min_replicas(key, b"value", 2)
+            .unwrap();
+        let mut expected = vec![bootstrap.node_data(), node.node_data()];
+        expected.sort_by_key(|node_data| node_data.id.xor(&key));
+        assert_eq!(confirmed, expected);
+
+        match bootstrap.insert_with_min_replicas(key, b"value", 3) {
+            Err(Error::InsufficientReplicas {
+                required: 3,
+                confirmed,
+            }) => assert_eq!(confirmed, expected),
+            other => panic!("unexpected result {:?}", other),
         }
     }
 
-    /// Returns the `NodeData` associated with the node.
-    pub fn node_data(&self) -> NodeData {
-        (*self.node_data).clone()
+    #[test]
+    fn test_insert_with_storage() {
+        let network = MemoryNetwork::new();
+        let storage = SharedStorage::default();
+        let bootstrap =
+            Node::with_storage(network.bind(), None, NodeConfig::default(), storage.clone())
+                .unwrap();
+        let mut node = Node::with_transport(
+            network.bind(),
+            Some(bootstrap.node_data()),
+            NodeConfig::default(),
+        )
+        .unwrap();
+
+        let key = Key::rand();
+        node.insert(key, b"value").unwrap();
+        assert_eq!(
+            storage.stats(),
+            StorageStats {
+                entries: 1,
+                bytes: 5,
+            }
+        );
+        assert_eq!(
+            storage.0.lock().unwrap().remove(&key).unwrap(),
+            Some(b"value".to_vec())
+        );
     }
 
-    /// Kills the current node and all active threads.
-    pub fn kill(&self) {
-        self.protocol.send_message(&Message::Kill, &self.node_data);
+    #[test]
+    fn test_per_node_config() {
+        let network = MemoryNetwork::new();
+        let bootstrap = Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..5 {
+            let node = Node::with_transport(
+                network.bind(),
+                Some(bootstrap.node_data()),
+                NodeConfig::default(),
+            )
+            .unwrap();
+            nodes.push(node);
+        }
+        let config = NodeConfig::builder().replication_param(2).build();
+        let mut small_node =
+            Node::with_transport(network.bind(), Some(bootstrap.node_data()), config).unwrap();
+
+        // each node stores a pair on as many nodes as its own replication parameter allows
+        let key = Key::new([1; 32]);
+        assert_eq!(small_node.insert(key, b"value").unwrap().len(), 2);
+        assert_eq!(nodes[0].insert(key, b"value").unwrap().len(), 7);
+    }
+
+    #[test]
+    fn test_config_id() {
+        let network = MemoryNetwork::new();
+        let id = Key::new([9; 32]);
+        let config = NodeConfig::builder().id(Some(id)).build();
+        let node = Node::with_transport(network.bind(), None, config).unwrap();
+        assert_eq!(node.node_data().id, id);
+    }
+
+    #[test]
+    fn test_killed_node_returns_shutdown() {
+        let network = MemoryNetwork::new();
+        let mut node = Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        node.kill();
+        assert!(node.get(&Key::new([0; 32])).is_err());
     }
 }
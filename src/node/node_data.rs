@@ -30,7 +30,7 @@ impl PartialEq for NodeDataDistancePair {
 
 impl PartialOrd for NodeDataDistancePair {
     fn partial_cmp(&self, other: &NodeDataDistancePair) -> Option<Ordering> {
-        Some(other.1.cmp(&self.1))
+        Some(self.cmp(other))
     }
 }
 
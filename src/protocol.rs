@@ -1,14 +1,19 @@
+use crate::clock::Clock;
+use crate::config::NodeConfig;
+use crate::error::{Error, Result};
 use crate::key::Key;
 use crate::node::node_data::NodeData;
-use crate::MESSAGE_LENGTH;
-use bincode;
-use log::{log, warn};
+use crate::transport::Transport;
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
-use std::net::UdpSocket;
-use std::str;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::thread;
+use time::{Duration, SteadyTime};
 
 /// An enum representing a request RPC.
 ///
@@ -24,11 +29,14 @@ pub struct Request {
 /// An enum representing the payload to a request RPC.
 ///
 /// As stated in the Kademlia paper, the four possible RPCs are `PING`, `STORE`, `FIND_NODE`, and
-/// `FIND_VALUE`.
+/// `FIND_VALUE`. A `STORE` RPC may carry a time-to-live for the key-value pair; without one, the
+/// pair expires after the receiver's `key_expiration`. `CacheStore` is a `STORE` RPC of a value
+/// found by a lookup that expires after the given time-to-live.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum RequestPayload {
     Ping,
-    Store(Key, String),
+    Store(Key, Vec<u8>, Option<std::time::Duration>),
+    CacheStore(Key, Vec<u8>, std::time::Duration),
     FindNode(Key),
     FindValue(Key),
 }
@@ -44,12 +52,27 @@ pub struct Response {
 /// An enum representing the payload to a response RPC.
 ///
 /// As stated in the Kademlia paper, a response to a request could be a list of nodes, a value, or
-/// a pong.
+/// a pong. A value is accompanied by its remaining lifetime on the responding node. A `STORE` RPC
+/// is answered with `StoreRejected` instead of a pong if the pair does not fit in the responding
+/// node's storage quota.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ResponsePayload {
     Nodes(Vec<NodeData>),
-    Value(String),
+    Value(Vec<u8>, std::time::Duration),
     Pong,
+    StoreRejected,
+}
+
+/// A piece of a message that does not fit in a single datagram.
+///
+/// The encoded message is split into `count` fragments that share the same `id`. The receiver
+/// concatenates the data of the fragments in order of `index` and decodes the result.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Fragment {
+    pub id: u64,
+    pub index: u32,
+    pub count: u32,
+    pub data: Vec<u8>,
 }
 
 /// An enum that represents a message that is sent between nodes.
@@ -58,28 +81,442 @@ pub enum Message {
     Request(Request),
     Response(Response),
     Kill,
+    Fragment(Fragment),
+}
+
+/// A snapshot of the counters describing the datagrams received by a node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProtocolStats {
+    /// The number of well-formed messages received.
+    pub messages_received: u64,
+    /// The number of datagrams that were rejected because they could not be decoded.
+    pub malformed_messages: u64,
+    /// The number of datagrams that were dropped because their source address is blacklisted.
+    pub blocked_messages: u64,
+    /// The number of messages that were dropped because they carry a value larger than
+    /// `max_value_size`.
+    pub oversized_messages: u64,
+}
+
+/// The counters that back `ProtocolStats`.
+#[derive(Default)]
+pub struct Metrics {
+    messages_received: AtomicU64,
+    malformed_messages: AtomicU64,
+    blocked_messages: AtomicU64,
+    oversized_messages: AtomicU64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> ProtocolStats {
+        ProtocolStats {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            malformed_messages: self.malformed_messages.load(Ordering::Relaxed),
+            blocked_messages: self.blocked_messages.load(Ordering::Relaxed),
+            oversized_messages: self.oversized_messages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Tracks the number of malformed datagrams sent by each address, and blacklists addresses that
+/// exceed `malformed_message_limit` for `blacklist_duration`.
+struct Blacklist {
+    strikes: HashMap<IpAddr, (usize, SteadyTime)>,
+    banned: HashMap<IpAddr, SteadyTime>,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Blacklist {
+    /// The number of tracked addresses above which stale strikes are pruned.
+    const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+    fn new(config: Arc<NodeConfig>, clock: Arc<dyn Clock>) -> Self {
+        Blacklist {
+            strikes: HashMap::new(),
+            banned: HashMap::new(),
+            config,
+            clock,
+        }
+    }
+
+    fn blacklist_duration(&self) -> Duration {
+        Duration::milliseconds(self.config.blacklist_duration.as_millis() as i64)
+    }
+
+    /// Returns `true` if `addr` is currently blacklisted.
+    fn is_banned(&mut self, addr: &IpAddr) -> bool {
+        match self.banned.get(addr) {
+            Some(banned_until) if *banned_until > self.clock.now() => true,
+            Some(_) => {
+                self.banned.remove(addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records a malformed datagram from `addr`, and blacklists `addr` if it exceeded the limit.
+    fn record_strike(&mut self, addr: IpAddr) {
+        let limit = match self.config.malformed_message_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let now = self.clock.now();
+        let blacklist_duration = self.blacklist_duration();
+
+        if self.strikes.len() >= Blacklist::MAX_TRACKED_ADDRESSES {
+            self.strikes
+                .retain(|_, (_, last_strike)| now - *last_strike < blacklist_duration);
+        }
+
+        let entry = self.strikes.entry(addr).or_insert((0, now));
+        entry.0 += 1;
+        entry.1 = now;
+        if entry.0 >= limit {
+            warn!(
+                "Protocol: Blacklisting {} after {} malformed messages.",
+                addr, entry.0
+            );
+            self.strikes.remove(&addr);
+            self.banned.insert(addr, now + blacklist_duration);
+        }
+    }
+}
+
+/// Reassembles messages that were split into fragments.
+///
+/// At most `MAX_PARTIAL_MESSAGES` messages are reassembled at once, and the fragments of a message
+/// that is not complete within `request_timeout` are discarded. A message whose fragments exceed
+/// the maximum message length is rejected as soon as the limit is crossed.
+struct Reassembler {
+    partial_messages: HashMap<(SocketAddr, u64), PartialMessage>,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
+}
+
+struct PartialMessage {
+    count: u32,
+    fragments: BTreeMap<u32, Vec<u8>>,
+    length: usize,
+    started: SteadyTime,
+}
+
+impl Reassembler {
+    /// The maximum number of messages that are reassembled at once.
+    const MAX_PARTIAL_MESSAGES: usize = 64;
+
+    fn new(config: Arc<NodeConfig>, clock: Arc<dyn Clock>) -> Self {
+        Reassembler {
+            partial_messages: HashMap::new(),
+            config,
+            clock,
+        }
+    }
+
+    /// Adds a fragment received from `src_addr`. Returns the encoded message once all of its
+    /// fragments have been received.
+    fn add(&mut self, src_addr: SocketAddr, fragment: Fragment) -> Result<Option<Vec<u8>>> {
+        let Fragment {
+            id,
+            index,
+            count,
+            data,
+        } = fragment;
+        let max_length = max_message_length(&self.config);
+        if index >= count || data.is_empty() || count as usize > max_length {
+            return Err(invalid_message("invalid fragment"));
+        }
+
+        let now = self.clock.now();
+        let key = (src_addr, id);
+        if !self.partial_messages.contains_key(&key) {
+            self.prune(now);
+        }
+        let partial_message = self
+            .partial_messages
+            .entry(key)
+            .or_insert_with(|| PartialMessage {
+                count,
+                fragments: BTreeMap::new(),
+                length: 0,
+                started: now,
+            });
+
+        if partial_message.count != count {
+            self.partial_messages.remove(&key);
+            return Err(invalid_message("inconsistent fragment count"));
+        }
+        if partial_message.fragments.contains_key(&index) {
+            return Ok(None);
+        }
+        partial_message.length += data.len();
+        if partial_message.length > max_length {
+            self.partial_messages.remove(&key);
+            return Err(invalid_message("fragmented message is too long"));
+        }
+        partial_message.fragments.insert(index, data);
+        if partial_message.fragments.len() < count as usize {
+            return Ok(None);
+        }
+
+        Ok(self
+            .partial_messages
+            .remove(&key)
+            .map(|partial_message| partial_message.fragments.into_values().flatten().collect()))
+    }
+
+    /// Discards expired partial messages, and the oldest partial message if there is no room for
+    /// another one.
+    fn prune(&mut self, now: SteadyTime) {
+        let timeout = Duration::milliseconds(self.config.request_timeout.as_millis() as i64);
+        self.partial_messages
+            .retain(|_, partial_message| now - partial_message.started < timeout);
+
+        if self.partial_messages.len() >= Reassembler::MAX_PARTIAL_MESSAGES {
+            let oldest = self
+                .partial_messages
+                .iter()
+                .min_by_key(|(_, partial_message)| partial_message.started)
+                .map(|(key, _)| *key);
+            if let Some(key) = oldest {
+                self.partial_messages.remove(&key);
+            }
+        }
+    }
+}
+
+/// Decodes the datagrams received by a node into messages.
+///
+/// Fragments are reassembled into the messages they were split from. Datagrams from blacklisted
+/// addresses, datagrams that cannot be decoded, and messages that carry a value larger than
+/// `max_value_size` are logged, counted in `metrics`, and dropped.
+pub struct Decoder {
+    blacklist: Blacklist,
+    reassembler: Reassembler,
+    metrics: Arc<Metrics>,
+    config: Arc<NodeConfig>,
+}
+
+impl Decoder {
+    pub fn new(metrics: Arc<Metrics>, config: Arc<NodeConfig>, clock: Arc<dyn Clock>) -> Self {
+        Decoder {
+            blacklist: Blacklist::new(Arc::clone(&config), Arc::clone(&clock)),
+            reassembler: Reassembler::new(Arc::clone(&config), clock),
+            metrics,
+            config,
+        }
+    }
+
+    /// Decodes a datagram received from `src_addr`. Returns `None` if the datagram was dropped, or
+    /// if it is a fragment of a message that has not been fully received yet.
+    pub fn decode(&mut self, datagram: &[u8], src_addr: SocketAddr) -> Option<Message> {
+        if self.blacklist.is_banned(&src_addr.ip()) {
+            self.metrics
+                .blocked_messages
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let message = match decode_message(datagram, self.config.message_length) {
+            Ok(Message::Fragment(fragment)) => match self.reassembler.add(src_addr, fragment) {
+                Ok(Some(bytes)) => match decode_message(&bytes, max_message_length(&self.config)) {
+                    Ok(Message::Fragment(_)) => Err(invalid_message("nested fragment")),
+                    message => message,
+                },
+                Ok(None) => return None,
+                Err(err) => Err(err),
+            },
+            message => message,
+        };
+
+        let message = match message {
+            // only the node itself is allowed to kill the node, which it does through `kill`
+            Ok(Message::Kill) => Err(invalid_message("kill message from remote address")),
+            message => message,
+        };
+
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(
+                    "Protocol: Rejected malformed message from {}: {}",
+                    src_addr, err
+                );
+                self.metrics
+                    .malformed_messages
+                    .fetch_add(1, Ordering::Relaxed);
+                self.blacklist.record_strike(src_addr.ip());
+                return None;
+            }
+        };
+        if let Err(err) = check_value_size(&message, self.config.max_value_size) {
+            warn!("Protocol: Rejected message from {}: {}", src_addr, err);
+            self.metrics
+                .oversized_messages
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.metrics
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+        Some(message)
+    }
+}
+
+/// The length of the encoding of a `Message::Fragment` without its data.
+const FRAGMENT_HEADER_LENGTH: usize = 28;
+
+fn invalid_message(reason: &str) -> Error {
+    Error::Serialization(Box::new(bincode::ErrorKind::Custom(reason.to_string())))
+}
+
+/// Returns the maximum length of a message in bytes after reassembly. The response to a `STORE`
+/// RPC includes the request, so a message may carry up to two values.
+pub fn max_message_length(config: &NodeConfig) -> usize {
+    config.message_length + 2 * config.max_value_size
+}
+
+/// Returns an error if `message` carries a value that is larger than `max_value_size` bytes.
+pub fn check_value_size(message: &Message, max_value_size: usize) -> Result<()> {
+    let request_value = |request: &Request| match request.payload {
+        RequestPayload::Store(_, ref value, _) | RequestPayload::CacheStore(_, ref value, _) => {
+            value.len()
+        }
+        _ => 0,
+    };
+    let size = match message {
+        Message::Request(request) => request_value(request),
+        Message::Response(response) => match response.payload {
+            ResponsePayload::Value(ref value, _) => value.len(),
+            _ => request_value(&response.request),
+        },
+        Message::Kill | Message::Fragment(_) => 0,
+    };
+    if size > max_value_size {
+        Err(Error::ValueTooLarge(size))
+    } else {
+        Ok(())
+    }
+}
+
+/// Encodes `message` into datagrams of at most `message_length` bytes. A message that does not fit
+/// in a single datagram is split into fragments identified by `id`.
+pub fn encode_datagrams(message: &Message, id: u64, config: &NodeConfig) -> Result<Vec<Vec<u8>>> {
+    let bytes = encode_message(message, max_message_length(config))?;
+    if bytes.len() <= config.message_length {
+        return Ok(vec![bytes]);
+    }
+    if config.message_length <= FRAGMENT_HEADER_LENGTH {
+        return Err(Error::Serialization(Box::new(
+            bincode::ErrorKind::SizeLimit,
+        )));
+    }
+
+    let chunks = bytes.chunks(config.message_length - FRAGMENT_HEADER_LENGTH);
+    let count = chunks.len() as u32;
+    chunks
+        .enumerate()
+        .map(|(index, data)| {
+            let fragment = Message::Fragment(Fragment {
+                id,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            });
+            encode_message(&fragment, config.message_length)
+        })
+        .collect()
+}
+
+/// Encodes `message` into a datagram of at most `message_length` bytes.
+pub fn encode_message(message: &Message, message_length: usize) -> Result<Vec<u8>> {
+    let size_limit = bincode::Bounded(message_length as u64);
+    Ok(bincode::serialize(message, size_limit)?)
+}
+
+/// Decodes a datagram into a `Message`. The datagram is rejected if it is longer than
+/// `message_length` bytes or if it is not a valid encoding of a `Message`.
+pub fn decode_message(bytes: &[u8], message_length: usize) -> Result<Message> {
+    if bytes.len() > message_length {
+        return Err(Error::Serialization(Box::new(
+            bincode::ErrorKind::SizeLimit,
+        )));
+    }
+    let size_limit = bincode::Bounded(message_length as u64);
+    let mut reader = bytes;
+    let message = bincode::deserialize_from(&mut reader, size_limit)?;
+    if !reader.is_empty() {
+        return Err(invalid_message("trailing bytes after message"));
+    }
+    Ok(message)
 }
 
 /// `Protocol` facilitates the underlying communication between nodes by sending messages to other
 /// nodes, and by passing messages from other nodes to the current node.
+///
+/// Messages longer than `message_length` are split into fragments by the sender and reassembled
+/// by the receiver. Messages that carry a value larger than `max_value_size` are dropped.
+///
+/// Datagrams that cannot be decoded are logged, counted, and dropped without interrupting the
+/// receiving thread. If `malformed_message_limit` is set, addresses that repeatedly send malformed
+/// datagrams are ignored for `blacklist_duration`. `Kill` datagrams are always rejected; the
+/// receiving thread is stopped by `kill` instead.
 #[derive(Clone)]
 pub struct Protocol {
-    socket: Arc<UdpSocket>,
+    transport: Arc<dyn Transport>,
+    metrics: Arc<Metrics>,
+    next_fragment_id: Arc<AtomicU64>,
+    is_killed: Arc<AtomicBool>,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Protocol {
-    pub fn new(socket: UdpSocket, tx: Sender<Message>) -> Protocol {
+    pub fn new<T>(
+        transport: Arc<dyn Transport>,
+        tx: Sender<T>,
+        config: Arc<NodeConfig>,
+        clock: Arc<dyn Clock>,
+    ) -> Protocol
+    where
+        T: From<Message> + Send + 'static,
+    {
         let protocol = Protocol {
-            socket: Arc::new(socket),
+            transport,
+            metrics: Arc::new(Metrics::default()),
+            next_fragment_id: Arc::new(AtomicU64::new(0)),
+            is_killed: Arc::new(AtomicBool::new(false)),
+            config,
+            clock,
         };
         let ret = protocol.clone();
         thread::spawn(move || {
-            let mut buffer = [0u8; MESSAGE_LENGTH];
+            let mut decoder = Decoder::new(
+                Arc::clone(&protocol.metrics),
+                Arc::clone(&protocol.config),
+                Arc::clone(&protocol.clock),
+            );
+            // one extra byte to detect datagrams that exceed the maximum message length
+            let mut buffer = vec![0u8; protocol.config.message_length + 1];
             loop {
-                let (len, _src_addr) = protocol.socket.recv_from(&mut buffer).unwrap();
-                let message = bincode::deserialize(&buffer[..len]).unwrap();
+                let received = protocol.transport.recv_from(&mut buffer);
+                if protocol.is_killed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let (len, src_addr) = match received {
+                    Ok(ret) => ret,
+                    Err(err) => {
+                        warn!("Protocol: Could not receive data: {}", err);
+                        continue;
+                    }
+                };
 
-                if tx.send(message).is_err() {
+                let message = match decoder.decode(&buffer[..len], src_addr) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if tx.send(T::from(message)).is_err() {
                     warn!("Protocol: Connection closed.");
                     break;
                 }
@@ -88,12 +525,371 @@ impl Protocol {
         ret
     }
 
-    pub fn send_message(&self, message: &Message, node_data: &NodeData) {
-        let size_limit = bincode::Bounded(MESSAGE_LENGTH as u64);
-        let buffer_string = bincode::serialize(&message, size_limit).unwrap();
+    pub fn send_message(&self, message: &Message, node_data: &NodeData) -> Result<()> {
+        let id = self.next_fragment_id.fetch_add(1, Ordering::Relaxed);
+        let datagrams = encode_datagrams(message, id, &self.config)?;
         let NodeData { ref addr, .. } = node_data;
-        if self.socket.send_to(&buffer_string, addr).is_err() {
-            warn!("Protocol: Could not send data.");
+        let dest = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve address")
+        })?;
+        for datagram in datagrams {
+            self.transport.send_to(&datagram, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Stops the receiving thread. An empty datagram is sent to the transport's own address to wake
+    /// up the thread; if the transport is bound to an unspecified address, it is sent to the
+    /// loopback address instead.
+    pub fn kill(&self) -> Result<()> {
+        self.is_killed.store(true, Ordering::Relaxed);
+        let mut addr = self.transport.local_addr()?;
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => {}
+        }
+        self.transport.send_to(&[], addr)?;
+        Ok(())
+    }
+
+    /// Returns a snapshot of the counters describing the datagrams received.
+    pub fn stats(&self) -> ProtocolStats {
+        self.metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_value_size, decode_message, encode_datagrams, encode_message, max_message_length,
+        Decoder, Fragment, Message, Metrics, Protocol, Reassembler, Request, RequestPayload,
+        FRAGMENT_HEADER_LENGTH,
+    };
+    use crate::clock::SystemClock;
+    use crate::config::NodeConfig;
+    use crate::error::Error;
+    use crate::key::Key;
+    use crate::node::node_data::NodeData;
+    use crate::sim::VirtualClock;
+    use crate::MESSAGE_LENGTH;
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn store_message(value: Vec<u8>) -> Message {
+        Message::Request(Request {
+            id: Key::rand(),
+            sender: NodeData {
+                addr: "127.0.0.1:8080".to_string(),
+                id: Key::rand(),
+            },
+            payload: RequestPayload::Store(Key::rand(), value, None),
+        })
+    }
+
+    fn valid_frame() -> Vec<u8> {
+        let message = Message::Request(Request {
+            id: Key::rand(),
+            sender: NodeData {
+                addr: "127.0.0.1:8080".to_string(),
+                id: Key::rand(),
+            },
+            payload: RequestPayload::Store(Key::rand(), b"value".to_vec(), None),
+        });
+        bincode::serialize(&message, bincode::Bounded(MESSAGE_LENGTH as u64)).unwrap()
+    }
+
+    #[test]
+    fn test_decode_valid_frame() {
+        let frame = valid_frame();
+        match decode_message(&frame, MESSAGE_LENGTH) {
+            Ok(Message::Request(Request {
+                payload: RequestPayload::Store(_, value, _),
+                ..
+            })) => assert_eq!(value, b"value"),
+            other => panic!("unexpected decode result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_random_bytes() {
+        let mut rng = StdRng::from_seed(&[0x6b, 0x61, 0x64]);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0, MESSAGE_LENGTH + 16);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let _ = decode_message(&bytes, MESSAGE_LENGTH);
+        }
+    }
+
+    #[test]
+    fn test_decode_mutated_frames() {
+        let mut rng = StdRng::from_seed(&[0x64, 0x68, 0x74]);
+        let frame = valid_frame();
+        for _ in 0..10_000 {
+            let mut bytes = frame.clone();
+            for _ in 0..rng.gen_range(1, 8) {
+                let index = rng.gen_range(0, bytes.len());
+                bytes[index] = rng.gen();
+            }
+            bytes.truncate(rng.gen_range(0, frame.len() + 1));
+            let _ = decode_message(&bytes, MESSAGE_LENGTH);
+        }
+    }
+
+    fn decoder(config: NodeConfig, clock: Arc<VirtualClock>) -> (Decoder, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let decoder = Decoder::new(Arc::clone(&metrics), Arc::new(config), clock);
+        (decoder, metrics)
+    }
+
+    #[test]
+    fn test_decoder_random_datagrams() {
+        let mut rng = StdRng::from_seed(&[0x72, 0x6e, 0x64]);
+        let config = NodeConfig::builder().message_length(512).build();
+        let (mut decoder, metrics) = decoder(config, Arc::new(VirtualClock::new()));
+        for i in 0..10_000 {
+            let len = rng.gen_range(0, 512 + 16);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let src_addr = SocketAddr::from(([127, 0, 0, 1], 8000 + i % 16));
+            assert!(decoder.decode(&bytes, src_addr).is_none());
+        }
+        let stats = metrics.snapshot();
+        assert_eq!(stats.messages_received, 0);
+        assert!(stats.malformed_messages > 0);
+    }
+
+    #[test]
+    fn test_decoder_mutated_fragments() {
+        let mut rng = StdRng::from_seed(&[0x66, 0x72, 0x67]);
+        let config = NodeConfig::builder().message_length(512).build();
+        let (mut decoder, metrics) = decoder(config.clone(), Arc::new(VirtualClock::new()));
+        let datagrams = encode_datagrams(&store_message(vec![7; 4000]), 0, &config).unwrap();
+        assert!(datagrams.len() > 1);
+
+        let mut received = 0;
+        for i in 0..1000 {
+            let src_addr = SocketAddr::from(([127, 0, 0, 1], 8000 + i % 16));
+            let mut datagrams = datagrams.clone();
+            let index = rng.gen_range(0, datagrams.len());
+            let datagram = &mut datagrams[index];
+            for _ in 0..rng.gen_range(0, 4) {
+                let byte = rng.gen_range(0, datagram.len());
+                datagram[byte] = rng.gen();
+            }
+            let len = rng.gen_range(0, datagram.len() + 1);
+            datagram.truncate(len);
+            rng.shuffle(&mut datagrams);
+            for datagram in &datagrams {
+                if decoder.decode(datagram, src_addr).is_some() {
+                    received += 1;
+                }
+            }
+        }
+        let stats = metrics.snapshot();
+        assert_eq!(stats.messages_received, received);
+        assert!(stats.malformed_messages > 0);
+    }
+
+    #[test]
+    fn test_decoder_rejects_nested_fragment() {
+        let config = NodeConfig::default();
+        let (mut decoder, metrics) = decoder(config.clone(), Arc::new(VirtualClock::new()));
+        let inner = encode_datagrams(&store_message(vec![0; 10]), 0, &config).unwrap();
+        let nested = Message::Fragment(Fragment {
+            id: 1,
+            index: 0,
+            count: 1,
+            data: encode_message(
+                &Message::Fragment(Fragment {
+                    id: 2,
+                    index: 0,
+                    count: 1,
+                    data: inner[0].clone(),
+                }),
+                MESSAGE_LENGTH,
+            )
+            .unwrap(),
+        });
+        let datagram = encode_message(&nested, MESSAGE_LENGTH).unwrap();
+        let src_addr = "127.0.0.1:8080".parse().unwrap();
+        assert!(decoder.decode(&datagram, src_addr).is_none());
+        assert_eq!(metrics.snapshot().malformed_messages, 1);
+    }
+
+    #[test]
+    fn test_decoder_reassembly_timeout() {
+        let clock = Arc::new(VirtualClock::new());
+        let config = NodeConfig::builder()
+            .message_length(512)
+            .request_timeout(Duration::from_secs(1))
+            .build();
+        let (mut decoder, _) = decoder(config.clone(), Arc::clone(&clock));
+        let src_addr = "127.0.0.1:8080".parse().unwrap();
+        let datagrams = encode_datagrams(&store_message(vec![0; 2000]), 0, &config).unwrap();
+        let other = encode_datagrams(&store_message(vec![0; 2000]), 1, &config).unwrap();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert!(decoder.decode(datagram, src_addr).is_none());
+        }
+
+        // the next new message prunes the partial message that timed out
+        clock.set_elapsed(Duration::from_secs(2));
+        let (other_last, other_rest) = other.split_last().unwrap();
+        for datagram in other_rest {
+            assert!(decoder.decode(datagram, src_addr).is_none());
+        }
+        assert!(decoder.decode(last, src_addr).is_none());
+        assert!(decoder.decode(other_last, src_addr).is_some());
+    }
+
+    #[test]
+    fn test_decoder_blacklist() {
+        let clock = Arc::new(VirtualClock::new());
+        let config = NodeConfig::builder()
+            .malformed_message_limit(Some(3))
+            .blacklist_duration(Duration::from_secs(60))
+            .build();
+        let (mut decoder, metrics) = decoder(config, Arc::clone(&clock));
+        let (banned, other) = (
+            "127.0.0.1:8080".parse().unwrap(),
+            "127.0.0.2:8080".parse().unwrap(),
+        );
+        for _ in 0..3 {
+            assert!(decoder.decode(b"garbage", banned).is_none());
         }
+        assert_eq!(metrics.snapshot().malformed_messages, 3);
+
+        assert!(decoder.decode(&valid_frame(), banned).is_none());
+        assert!(decoder.decode(&valid_frame(), other).is_some());
+        let stats = metrics.snapshot();
+        assert_eq!(stats.blocked_messages, 1);
+        assert_eq!(stats.messages_received, 1);
+
+        clock.set_elapsed(Duration::from_secs(61));
+        assert!(decoder.decode(&valid_frame(), banned).is_some());
+        assert_eq!(metrics.snapshot().blocked_messages, 1);
+    }
+
+    #[test]
+    fn test_decode_oversized_length_prefix() {
+        let mut frame = valid_frame();
+        // the length prefix of the sender's address starts after the variant tag and request id
+        let offset = 4 + 32;
+        frame[offset..offset + 8].copy_from_slice(&[0xFF; 8]);
+        assert!(decode_message(&frame, MESSAGE_LENGTH).is_err());
+    }
+
+    #[test]
+    fn test_decode_oversized_frame() {
+        let bytes = vec![0u8; MESSAGE_LENGTH + 1];
+        assert!(decode_message(&bytes, MESSAGE_LENGTH).is_err());
+    }
+
+    #[test]
+    fn test_decode_trailing_bytes() {
+        let mut frame = valid_frame();
+        frame.push(0);
+        assert!(decode_message(&frame, MESSAGE_LENGTH).is_err());
+    }
+
+    #[test]
+    fn test_fragment_header_length() {
+        let fragment = Message::Fragment(Fragment {
+            id: 0,
+            index: 0,
+            count: 1,
+            data: vec![0; 100],
+        });
+        let bytes = encode_message(&fragment, MESSAGE_LENGTH).unwrap();
+        assert_eq!(bytes.len(), 100 + FRAGMENT_HEADER_LENGTH);
+    }
+
+    #[test]
+    fn test_reassemble_fragments() {
+        let config = Arc::new(NodeConfig::builder().message_length(512).build());
+        let value: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let datagrams = encode_datagrams(&store_message(value.clone()), 7, &config).unwrap();
+        assert!(datagrams.len() > 1);
+
+        let mut reassembler = Reassembler::new(Arc::clone(&config), Arc::new(SystemClock));
+        let src_addr = "127.0.0.1:8080".parse().unwrap();
+        let mut bytes = None;
+        for datagram in datagrams.iter().rev() {
+            assert!(datagram.len() <= config.message_length);
+            match decode_message(datagram, config.message_length).unwrap() {
+                Message::Fragment(fragment) => {
+                    assert!(bytes.is_none());
+                    bytes = reassembler.add(src_addr, fragment).unwrap();
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+
+        match decode_message(&bytes.unwrap(), max_message_length(&config)) {
+            Ok(Message::Request(Request {
+                payload: RequestPayload::Store(_, received, _),
+                ..
+            })) => assert_eq!(received, value),
+            other => panic!("unexpected decode result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reassemble_rejects_long_message() {
+        let sender_config = NodeConfig::builder().message_length(512).build();
+        let receiver_config = NodeConfig::builder()
+            .message_length(512)
+            .max_value_size(1000)
+            .build();
+        let datagrams =
+            encode_datagrams(&store_message(vec![0; 10_000]), 7, &sender_config).unwrap();
+
+        let mut reassembler = Reassembler::new(Arc::new(receiver_config), Arc::new(SystemClock));
+        let src_addr = "127.0.0.1:8080".parse().unwrap();
+        let results: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| match decode_message(datagram, 512).unwrap() {
+                Message::Fragment(fragment) => reassembler.add(src_addr, fragment),
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        assert!(results.iter().any(|result| result.is_err()));
+        assert!(results.iter().all(|result| match result {
+            Ok(bytes) => bytes.is_none(),
+            Err(_) => true,
+        }));
+    }
+
+    #[test]
+    fn test_check_value_size() {
+        let message = store_message(vec![0; 1001]);
+        assert!(check_value_size(&message, 1001).is_ok());
+        match check_value_size(&message, 1000) {
+            Err(Error::ValueTooLarge(1001)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_kill_unspecified_address() {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let (tx, rx) = channel::<Message>();
+        let protocol = Protocol::new(
+            Arc::new(socket),
+            tx,
+            Arc::new(NodeConfig::default()),
+            Arc::new(SystemClock),
+        );
+
+        protocol.kill().unwrap();
+        // the receiving thread drops its sender once it stops
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).err(),
+            Some(RecvTimeoutError::Disconnected),
+        );
+        assert_eq!(protocol.stats().malformed_messages, 0);
     }
 }
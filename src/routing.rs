@@ -1,91 +1,260 @@
+use crate::clock::Clock;
+use crate::config::NodeConfig;
+use crate::error::Result;
 use crate::key::Key;
 use crate::node::node_data::NodeData;
-use crate::{BUCKET_REFRESH_INTERVAL, REPLICATION_PARAM, ROUTING_TABLE_SIZE};
+use crate::ROUTING_TABLE_SIZE;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::mem;
+use std::path::Path;
 use std::sync::Arc;
-use std::{cmp, mem};
 use time::{Duration, SteadyTime};
 
-/// A k-bucket in a node's routing table that has a maximum capacity of `REPLICATION_PARAM`.
+/// The id of a node and the contents of its routing table.
 ///
-/// The nodes in the k-bucket are sorted by the time of the most recent communication with those
-/// which have been most recently communicated at the end of the list.
+/// A snapshot is taken with `Node::snapshot` and can be saved to a file, so that a restarted node
+/// can rejoin the DHT with its old id and contacts by using `Node::from_snapshot`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RoutingSnapshot {
+    /// The id of the node.
+    pub id: Key,
+    /// The nodes in the routing table of the node.
+    pub nodes: Vec<NodeData>,
+}
+
+impl RoutingSnapshot {
+    /// Writes the snapshot to the file at `path`, replacing its contents.
+    ///
+    /// Returns an error if the snapshot could not be serialized or written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot from the file at `path`.
+    ///
+    /// Returns an error if the file could not be read or does not contain a snapshot.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+/// A node in a k-bucket and the health of the node.
+#[derive(Clone, Debug)]
+struct RoutingEntry {
+    node_data: NodeData,
+    /// The number of consecutive requests to the node that timed out.
+    failures: usize,
+    /// The time of the most recent communication with the node.
+    last_seen: SteadyTime,
+    /// The smoothed round-trip time of the requests to the node.
+    rtt: Option<Duration>,
+}
+
+impl RoutingEntry {
+    /// Constructs a new `RoutingEntry` for a node that was just seen.
+    fn new(node_data: NodeData, now: SteadyTime) -> Self {
+        RoutingEntry {
+            node_data,
+            failures: 0,
+            last_seen: now,
+            rtt: None,
+        }
+    }
+
+    /// Returns `true` if the node has failed a request since it was last seen, or if it has not
+    /// been seen in `questionable_interval`.
+    fn is_questionable(&self, questionable_interval: std::time::Duration, now: SteadyTime) -> bool {
+        let time_diff = now - self.last_seen;
+        self.failures > 0
+            || time_diff >= Duration::milliseconds(questionable_interval.as_millis() as i64)
+    }
+}
+
+/// A k-bucket in a node's routing table that has a maximum capacity of `replication_param`.
+///
+/// The k-bucket covers the keys whose first `depth` bits are the same as those of `prefix`. The
+/// nodes in the k-bucket are sorted by the time of the most recent communication with those
+/// which have been most recently communicated at the end of the list. Nodes that were seen while
+/// the k-bucket was full are kept in a replacement cache of the same capacity, in the same order,
+/// and replace the nodes that are removed from the k-bucket.
 #[derive(Clone, Debug)]
 struct RoutingBucket {
-    nodes: Vec<NodeData>,
+    prefix: Key,
+    depth: usize,
+    nodes: Vec<RoutingEntry>,
+    replacements: Vec<RoutingEntry>,
     last_update_time: SteadyTime,
 }
 
 impl RoutingBucket {
-    /// Constructs a new, empty `RoutingBucket`.
-    fn new() -> Self {
+    /// Constructs a new, empty `RoutingBucket` that covers the keys with `prefix`.
+    fn new(prefix: Key, depth: usize, now: SteadyTime) -> Self {
         RoutingBucket {
+            prefix,
+            depth,
             nodes: Vec::new(),
-            last_update_time: SteadyTime::now(),
+            replacements: Vec::new(),
+            last_update_time: now,
         }
     }
 
+    /// Removes `node_data` from the routing bucket or its replacement cache and returns its entry
+    /// marked as seen at `now`, or a new entry if the node is in neither.
+    fn take_entry(&mut self, node_data: NodeData, now: SteadyTime) -> RoutingEntry {
+        let mut entry = if let Some(index) = self
+            .nodes
+            .iter()
+            .position(|entry| entry.node_data == node_data)
+        {
+            self.nodes.remove(index)
+        } else if let Some(index) = self
+            .replacements
+            .iter()
+            .position(|entry| entry.node_data == node_data)
+        {
+            self.replacements.remove(index)
+        } else {
+            return RoutingEntry::new(node_data, now);
+        };
+        entry.failures = 0;
+        entry.last_seen = now;
+        entry
+    }
+
     /// Upserts a node in the routing bucket. If the node already exists in the routing bucket, the
-    /// node will be moved to the end of the list. If the routing bucket is at capacity, it will
-    /// remove the node least recently communicated with to create room for the new node.
-    /// Additionally, `last_update_time` is also updated.
-    fn update_node(&mut self, node_data: NodeData) {
-        self.last_update_time = SteadyTime::now();
-        if let Some(index) = self.nodes.iter().position(|data| *data == node_data) {
-            self.nodes.remove(index);
-        }
-        self.nodes.push(node_data);
-        if self.nodes.len() > REPLICATION_PARAM {
-            self.nodes.remove(0);
+    /// node will be moved to the end of the list. The caller must ensure that a new node fits in
+    /// the routing bucket. Additionally, `last_update_time` is also updated.
+    fn update_node(&mut self, node_data: NodeData, now: SteadyTime) {
+        self.last_update_time = now;
+        let entry = self.take_entry(node_data, now);
+        self.nodes.push(entry);
+    }
+
+    /// Upserts a node in the replacement cache of the routing bucket. If the replacement cache is
+    /// at capacity, the node least recently communicated with is dropped from the cache.
+    fn update_replacement(&mut self, node_data: NodeData, capacity: usize, now: SteadyTime) {
+        let entry = self.take_entry(node_data, now);
+        self.replacements.push(entry);
+        if self.replacements.len() > capacity {
+            self.replacements.remove(0);
         }
     }
 
+    /// Returns `true` if `key` is in the range of keys covered by the routing bucket.
+    fn covers(&self, key: &Key) -> bool {
+        key.xor(&self.prefix).leading_zeros() >= self.depth
+    }
+
     /// Returns `true` if the `node_data` exists in the routing bucket.
     fn contains(&self, node_data: &NodeData) -> bool {
-        self.nodes.iter().any(|data| data == node_data)
+        self.nodes.iter().any(|entry| entry.node_data == *node_data)
+    }
+
+    /// Returns the entry of `node_data` in the routing bucket.
+    fn get_entry(&self, node_data: &NodeData) -> Option<&RoutingEntry> {
+        self.nodes
+            .iter()
+            .find(|entry| entry.node_data == *node_data)
+    }
+
+    /// Returns the mutable entry of `node_data` in the routing bucket.
+    fn get_entry_mut(&mut self, node_data: &NodeData) -> Option<&mut RoutingEntry> {
+        self.nodes
+            .iter_mut()
+            .find(|entry| entry.node_data == *node_data)
     }
 
-    /// Splits `self` by a particular index and returns the closer bucket.
-    fn split(&mut self, key: &Key, index: usize) -> RoutingBucket {
-        let (old_bucket, new_bucket) = self
+    /// Splits `self` in half by the bit after its prefix. `self` keeps the half whose bit is zero
+    /// and the half whose bit is one is returned.
+    fn split(&mut self) -> RoutingBucket {
+        let mut new_bucket = RoutingBucket::new(
+            self.prefix.with_bit(self.depth),
+            self.depth + 1,
+            self.last_update_time,
+        );
+        self.depth += 1;
+        let (new_nodes, old_nodes) = self
             .nodes
             .drain(..)
-            .partition(|node| node.id.xor(key).leading_zeros() == index);
-        mem::replace(&mut self.nodes, old_bucket);
-        RoutingBucket {
-            nodes: new_bucket,
-            last_update_time: self.last_update_time,
+            .partition(|entry| new_bucket.covers(&entry.node_data.id));
+        let (new_replacements, old_replacements) = self
+            .replacements
+            .drain(..)
+            .partition(|entry| new_bucket.covers(&entry.node_data.id));
+        self.nodes = old_nodes;
+        self.replacements = old_replacements;
+        new_bucket.nodes = new_nodes;
+        new_bucket.replacements = new_replacements;
+        new_bucket
+    }
+
+    /// Returns `true` if the routing bucket can be split. A bucket is split if its range contains
+    /// `id`, or if `relaxed_splitting` is set to `b` and its depth is not a multiple of `b`.
+    fn can_split(&self, id: &Key, relaxed_splitting: Option<usize>) -> bool {
+        if self.depth + 1 >= ROUTING_TABLE_SIZE {
+            return false;
+        }
+        match relaxed_splitting {
+            _ if self.covers(id) => true,
+            // `usize::is_multiple_of` would require Rust 1.87
+            Some(depth) if depth > 1 => self.depth.checked_rem(depth) != Some(0),
+            _ => false,
         }
     }
 
-    /// Returns a slice of the nodes contained by the routing bucket.
-    fn get_nodes(&self) -> &[NodeData] {
-        self.nodes.as_slice()
+    /// Returns an iterator over the nodes contained by the routing bucket.
+    fn get_nodes(&self) -> impl Iterator<Item = &NodeData> {
+        self.nodes.iter().map(|entry| &entry.node_data)
     }
 
-    /// Removes the least recently seen node from the routing bucket.
-    fn remove_lrs(&mut self) -> Option<NodeData> {
-        if self.size() == 0 {
-            None
-        } else {
-            Some(self.nodes.remove(0))
-        }
+    /// Returns the least recently seen questionable node in the routing bucket.
+    fn questionable(
+        &self,
+        questionable_interval: std::time::Duration,
+        now: SteadyTime,
+    ) -> Option<&NodeData> {
+        self.nodes
+            .iter()
+            .find(|entry| entry.is_questionable(questionable_interval, now))
+            .map(|entry| &entry.node_data)
     }
 
-    /// Removes `node_data` from the routing bucket.
+    /// Removes `node_data` from the routing bucket and its replacement cache. A node removed from
+    /// the routing bucket is replaced by the most recently seen node in the replacement cache.
     pub fn remove_node(&mut self, node_data: &NodeData) -> Option<NodeData> {
-        if let Some(index) = self.nodes.iter().position(|data| data == node_data) {
-            Some(self.nodes.remove(index))
-        } else {
-            None
+        if let Some(index) = self
+            .replacements
+            .iter()
+            .position(|entry| entry.node_data == *node_data)
+        {
+            self.replacements.remove(index);
+        }
+        let index = self
+            .nodes
+            .iter()
+            .position(|entry| entry.node_data == *node_data)?;
+        let removed = self.nodes.remove(index);
+        if let Some(replacement) = self.replacements.pop() {
+            let index = self
+                .nodes
+                .iter()
+                .position(|entry| entry.last_seen > replacement.last_seen)
+                .unwrap_or(self.nodes.len());
+            self.nodes.insert(index, replacement);
         }
+        Some(removed.node_data)
     }
 
     /// Returns `true` if the routing bucket has not been recently updated.
     ///
-    /// A bucket is stale if it has not been updated in `BUCKET_REFRESH_INTERVAL` seconds.
-    pub fn is_stale(&self) -> bool {
-        let time_diff = SteadyTime::now() - self.last_update_time;
-        time_diff > Duration::seconds(BUCKET_REFRESH_INTERVAL as i64)
+    /// A bucket is stale if it has not been updated in `refresh_interval`.
+    pub fn is_stale(&self, refresh_interval: std::time::Duration, now: SteadyTime) -> bool {
+        let time_diff = now - self.last_update_time;
+        time_diff > Duration::milliseconds(refresh_interval.as_millis() as i64)
     }
 
     /// Returns the number of nodes in the routing bucket.
@@ -94,124 +263,401 @@ impl RoutingBucket {
     }
 }
 
-/// A node's routing table tree.
+/// A node in the binary trie of k-buckets.
 ///
-/// `RoutingTable` is implemented using a growable vector of `RoutingBucket`. The relaxation of
-/// k-bucket splitting proposed in Section 4.2 is not implemented.
+/// Each leaf holds a k-bucket, and the two children of a branch at depth `d` cover the keys whose
+/// bit `d` is zero and one respectively.
 #[derive(Clone, Debug)]
+enum RoutingNode {
+    Leaf(RoutingBucket),
+    Branch(Box<[RoutingNode; 2]>),
+}
+
+impl RoutingNode {
+    /// Returns the routing bucket that `key` belongs to.
+    fn bucket(&self, key: &Key) -> &RoutingBucket {
+        let mut node = self;
+        let mut depth = 0;
+        loop {
+            match node {
+                RoutingNode::Leaf(bucket) => return bucket,
+                RoutingNode::Branch(children) => node = &children[key.bit(depth)],
+            }
+            depth += 1;
+        }
+    }
+
+    /// Returns the mutable routing bucket that `key` belongs to.
+    fn bucket_mut(&mut self, key: &Key) -> &mut RoutingBucket {
+        let mut node = self;
+        let mut depth = 0;
+        loop {
+            match node {
+                RoutingNode::Leaf(bucket) => return bucket,
+                RoutingNode::Branch(children) => node = &mut children[key.bit(depth)],
+            }
+            depth += 1;
+        }
+    }
+
+    /// Splits the leaf that `key` belongs to into a branch with two leaves.
+    fn split(&mut self, key: &Key, depth: usize) {
+        match self {
+            RoutingNode::Leaf(bucket) => {
+                let one = bucket.split();
+                let zero = mem::replace(
+                    bucket,
+                    RoutingBucket::new(Key::default(), 0, one.last_update_time),
+                );
+                *self = RoutingNode::Branch(Box::new([
+                    RoutingNode::Leaf(zero),
+                    RoutingNode::Leaf(one),
+                ]));
+            }
+            RoutingNode::Branch(children) => children[key.bit(depth)].split(key, depth + 1),
+        }
+    }
+
+    /// Appends the routing buckets in the subtree to `ret` from left to right.
+    fn buckets<'a>(&'a self, ret: &mut Vec<&'a RoutingBucket>) {
+        match self {
+            RoutingNode::Leaf(bucket) => ret.push(bucket),
+            RoutingNode::Branch(children) => {
+                children[0].buckets(ret);
+                children[1].buckets(ret);
+            }
+        }
+    }
+
+    /// Appends the nodes in the subtree to `ret` in increasing order of their distance to `key` by
+    /// visiting the routing buckets in the same order, until `ret` has at least `count` nodes.
+    fn closest_nodes<'a>(
+        &'a self,
+        key: &Key,
+        count: usize,
+        depth: usize,
+        ret: &mut Vec<&'a NodeData>,
+    ) {
+        if ret.len() >= count {
+            return;
+        }
+        match self {
+            RoutingNode::Leaf(bucket) => {
+                // the nodes in the previously visited buckets are all closer to the key, so only the
+                // nodes of this bucket need to be sorted
+                let start = ret.len();
+                ret.extend(bucket.get_nodes());
+                ret[start..].sort_unstable_by_key(|node| node.id.xor(key));
+            }
+            RoutingNode::Branch(children) => {
+                // every key in the child that shares bit `depth` with `key` is closer to it than
+                // every key in the other child
+                let bit = key.bit(depth);
+                children[bit].closest_nodes(key, count, depth + 1, ret);
+                children[1 - bit].closest_nodes(key, count, depth + 1, ret);
+            }
+        }
+    }
+}
+
+/// A node's routing table tree.
+///
+/// `RoutingTable` is implemented as a binary trie of `RoutingBucket` keyed by the prefixes of the
+/// keys that each bucket covers. A full bucket is split if its range contains the node's id. If
+/// `relaxed_splitting` is set to `b`, the relaxation of k-bucket splitting proposed in Section 4.2
+/// also splits a full bucket whose depth is not a multiple of `b`, so that the subtrees of depth
+/// `b` next to the node's id are fully expanded.
+#[derive(Clone)]
 pub struct RoutingTable {
-    buckets: Vec<RoutingBucket>,
+    root: RoutingNode,
     node_data: Arc<NodeData>,
+    config: Arc<NodeConfig>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RoutingTable {
     /// Constructs a new, empty `RoutingTable`.
-    pub fn new(node_data: Arc<NodeData>) -> Self {
-        let mut buckets = Vec::new();
-        buckets.push(RoutingBucket::new());
-        RoutingTable { buckets, node_data }
+    pub fn new(node_data: Arc<NodeData>, config: Arc<NodeConfig>, clock: Arc<dyn Clock>) -> Self {
+        RoutingTable {
+            root: RoutingNode::Leaf(RoutingBucket::new(Key::default(), 0, clock.now())),
+            node_data,
+            config,
+            clock,
+        }
     }
 
     /// Upserts a node into the routing table. It will continue to split the routing table until the
-    /// routing table is full or until the node can be upserted.
+    /// node's k-bucket cannot be split or until the node can be upserted. Returns `false` if the
+    /// node's k-bucket is full, in which case the node is upserted into the k-bucket's replacement
+    /// cache instead.
     pub fn update_node(&mut self, node_data: NodeData) -> bool {
-        let distance = self.node_data.id.xor(&node_data.id).leading_zeros();
-        let mut target_bucket = cmp::min(distance, self.buckets.len() - 1);
-
-        if self.buckets[target_bucket].contains(&node_data) {
-            self.buckets[target_bucket].update_node(node_data);
-            return true;
-        }
+        let capacity = self.config.replication_param;
+        let now = self.clock.now();
 
         loop {
-            // bucket is not full
-            if self.buckets[target_bucket].size() < REPLICATION_PARAM {
-                self.buckets[target_bucket].update_node(node_data);
+            let bucket = self.root.bucket_mut(&node_data.id);
+
+            // node exists or bucket is not full
+            if bucket.contains(&node_data) || bucket.size() < capacity {
+                bucket.update_node(node_data, now);
                 return true;
             }
 
-            let is_last_bucket = target_bucket == self.buckets.len() - 1;
-            let is_full = self.buckets.len() == ROUTING_TABLE_SIZE;
-
             // bucket cannot be split
-            if !is_last_bucket || is_full {
+            if !bucket.can_split(&self.node_data.id, self.config.relaxed_splitting) {
+                bucket.update_replacement(node_data, capacity, now);
                 return false;
             }
 
             // split bucket
-            let new_bucket = self.buckets[target_bucket].split(&self.node_data.id, target_bucket);
-            self.buckets.push(new_bucket);
-
-            target_bucket = cmp::min(distance, self.buckets.len() - 1);
+            self.root.split(&node_data.id, 0);
         }
     }
 
-    /// Returns the closest `count` nodes to `key`.
+    /// Returns the closest `count` nodes to `key`. Only the routing buckets that are closer to
+    /// `key` than the `count`-th closest node are visited.
     pub fn get_closest_nodes(&self, key: &Key, count: usize) -> Vec<NodeData> {
-        let index = cmp::min(
-            self.node_data.id.xor(key).leading_zeros(),
-            self.buckets.len() - 1,
-        );
+        let mut ret = Vec::with_capacity(count + self.config.replication_param);
+        self.root.closest_nodes(key, count, 0, &mut ret);
+        ret.into_iter().take(count).cloned().collect()
+    }
+
+    /// Returns the routing buckets in the routing table.
+    fn buckets(&self) -> Vec<&RoutingBucket> {
         let mut ret = Vec::new();
+        self.root.buckets(&mut ret);
+        ret
+    }
 
-        // the closest keys are guaranteed to be in bucket which the key would reside
-        ret.extend_from_slice(self.buckets[index].get_nodes());
+    /// Returns `true` if `node_data` exists in the routing table.
+    pub fn contains(&self, node_data: &NodeData) -> bool {
+        self.root.bucket(&node_data.id).contains(node_data)
+    }
 
-        if ret.len() < count {
-            // the distance between target key and keys is not necessarily monotonic
-            // in range (key.leading_zeros(), self.buckets.len()], so we must iterate
-            for i in (index + 1)..self.buckets.len() {
-                ret.extend_from_slice(self.buckets[i].get_nodes());
-            }
-        }
+    /// Returns the least recently seen questionable node in the routing bucket that `key` belongs
+    /// to. A node is questionable if a request to it has timed out since it was last seen, or if it
+    /// has not been seen in `questionable_interval`.
+    pub fn questionable(&self, key: &Key) -> Option<NodeData> {
+        let now = self.clock.now();
+        self.root
+            .bucket(key)
+            .questionable(self.config.questionable_interval, now)
+            .cloned()
+    }
 
-        if ret.len() < count {
-            // the distance between target key and keys in [0, key.leading_zeros())
-            // is monotonicly decreasing by bucket
-            for i in (0..index).rev() {
-                ret.extend_from_slice(self.buckets[i].get_nodes());
-                if ret.len() >= count {
-                    break;
-                }
+    /// Records a request to `node_data` that timed out. The node is removed from the routing table
+    /// once `max_failures` consecutive requests to it have timed out. Returns `true` if the node
+    /// was removed.
+    pub fn record_failure(&mut self, node_data: &NodeData) -> bool {
+        let max_failures = self.config.max_failures;
+        let bucket = self.root.bucket_mut(&node_data.id);
+        let is_failed = match bucket.get_entry_mut(node_data) {
+            Some(entry) => {
+                entry.failures += 1;
+                entry.failures >= max_failures
             }
+            None => false,
+        };
+        if is_failed {
+            bucket.remove_node(node_data);
         }
+        is_failed
+    }
 
-        ret.sort_by_key(|node| node.id.xor(key));
-        ret.truncate(count);
-        ret
+    /// Records the round-trip time of a request to `node_data` in its smoothed round-trip time.
+    pub fn record_rtt(&mut self, node_data: &NodeData, rtt: Duration) {
+        if let Some(entry) = self.root.bucket_mut(&node_data.id).get_entry_mut(node_data) {
+            entry.rtt = Some(match entry.rtt {
+                Some(srtt) => (srtt * 7 + rtt) / 8,
+                None => rtt,
+            });
+        }
     }
 
-    /// Removes the least recently seen node from a particular routing bucket in the routing table.
-    pub fn remove_lrs(&mut self, key: &Key) -> Option<NodeData> {
-        let index = cmp::min(
-            self.node_data.id.xor(key).leading_zeros(),
-            self.buckets.len() - 1,
-        );
-        self.buckets[index].remove_lrs()
+    /// Returns the smoothed round-trip time of the requests to `node_data`.
+    pub fn rtt(&self, node_data: &NodeData) -> Option<Duration> {
+        self.root
+            .bucket(&node_data.id)
+            .get_entry(node_data)
+            .and_then(|entry| entry.rtt)
     }
 
-    /// Removes `node_data` from the routing table.
+    /// Removes `node_data` from the routing table. If it was in a k-bucket, its place is taken by
+    /// the most recently seen node in the k-bucket's replacement cache.
     pub fn remove_node(&mut self, node_data: &NodeData) {
-        let index = cmp::min(
-            self.node_data.id.xor(&node_data.id).leading_zeros(),
-            self.buckets.len() - 1,
-        );
-        self.buckets[index].remove_node(node_data);
+        self.root.bucket_mut(&node_data.id).remove_node(node_data);
     }
 
-    /// Returns a list of all the stale routing buckets in the routing table.
-    pub fn get_stale_indexes(&self) -> Vec<usize> {
-        let mut ret = Vec::new();
-        for (i, bucket) in self.buckets.iter().enumerate() {
-            if bucket.is_stale() {
-                ret.push(i);
+    /// Returns the prefixes of all the stale routing buckets in the routing table, each with the
+    /// number of bits in the prefix.
+    pub fn get_stale_prefixes(&self) -> Vec<(Key, usize)> {
+        let now = self.clock.now();
+        self.buckets()
+            .into_iter()
+            .filter(|bucket| bucket.is_stale(self.config.bucket_refresh_interval, now))
+            .map(|bucket| (bucket.prefix, bucket.depth))
+            .collect()
+    }
+
+    /// Returns the prefixes of all the routing buckets in the routing table, each with the number
+    /// of bits in the prefix.
+    pub fn prefixes(&self) -> Vec<(Key, usize)> {
+        self.buckets()
+            .into_iter()
+            .map(|bucket| (bucket.prefix, bucket.depth))
+            .collect()
+    }
+
+    /// Returns all of the nodes in the routing table.
+    pub fn nodes(&self) -> Vec<NodeData> {
+        self.buckets()
+            .into_iter()
+            .flat_map(|bucket| bucket.get_nodes().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoutingSnapshot, RoutingTable};
+    use crate::config::NodeConfig;
+    use crate::key::Key;
+    use crate::node::node_data::NodeData;
+    use crate::sim::{Simulation, VirtualClock};
+    use rand::{SeedableRng, StdRng};
+    use std::sync::Arc;
+    use time::Duration;
+
+    fn node_data(byte: u8, port: u16) -> NodeData {
+        NodeData {
+            addr: format!("127.0.0.1:{}", port),
+            id: Key::new([byte; 32]),
+        }
+    }
+
+    fn routing_table(config: NodeConfig, clock: &Arc<VirtualClock>) -> RoutingTable {
+        RoutingTable::new(
+            Arc::new(node_data(0, 8080)),
+            Arc::new(config),
+            Arc::clone(clock) as Arc<_>,
+        )
+    }
+
+    #[test]
+    fn test_replacement_cache() {
+        let config = NodeConfig::builder()
+            .replication_param(1)
+            .max_failures(2)
+            .build();
+        let mut routing_table = routing_table(config, &Arc::new(VirtualClock::new()));
+        let (lrs, candidate) = (node_data(0x80, 8081), node_data(0x81, 8082));
+
+        assert!(routing_table.update_node(lrs.clone()));
+        assert!(!routing_table.update_node(candidate.clone()));
+        assert_eq!(routing_table.nodes(), vec![lrs.clone()]);
+        assert_eq!(routing_table.questionable(&candidate.id), None);
+
+        assert!(!routing_table.record_failure(&lrs));
+        assert_eq!(routing_table.questionable(&candidate.id), Some(lrs.clone()));
+        assert!(routing_table.record_failure(&lrs));
+        assert_eq!(routing_table.nodes(), vec![candidate]);
+    }
+
+    #[test]
+    fn test_health() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut routing_table = routing_table(NodeConfig::default(), &clock);
+        let node = node_data(0x80, 8081);
+
+        routing_table.update_node(node.clone());
+        routing_table.record_rtt(&node, Duration::milliseconds(80));
+        routing_table.record_rtt(&node, Duration::milliseconds(160));
+        assert_eq!(routing_table.rtt(&node), Some(Duration::milliseconds(90)));
+
+        clock.set_elapsed(std::time::Duration::from_secs(901));
+        assert_eq!(routing_table.questionable(&node.id), Some(node.clone()));
+        routing_table.update_node(node.clone());
+        assert_eq!(routing_table.questionable(&node.id), None);
+    }
+
+    #[test]
+    fn test_relaxed_splitting() {
+        let clock = Arc::new(VirtualClock::new());
+        let coverage = |relaxed_splitting| {
+            let config = NodeConfig::builder()
+                .replication_param(2)
+                .relaxed_splitting(relaxed_splitting)
+                .build();
+            let mut routing_table = routing_table(config, &clock);
+            for i in 0..8 {
+                routing_table.update_node(node_data(0x80 | (i << 4), 8081 + u16::from(i)));
+            }
+            routing_table.nodes().len()
+        };
+
+        // only the bucket that contains the node's id is split
+        assert_eq!(coverage(None), 2);
+        // the bucket at depth 1 is split into two buckets at depth 2
+        assert_eq!(coverage(Some(2)), 4);
+        // the buckets at depths 1 and 2 are split into four buckets at depth 3
+        assert_eq!(coverage(Some(3)), 8);
+    }
+
+    #[test]
+    fn test_get_closest_nodes() {
+        let mut rng = StdRng::from_seed(&[0x6b, 0x61, 0x64]);
+        let config = NodeConfig::builder()
+            .replication_param(4)
+            .relaxed_splitting(Some(3))
+            .build();
+        let mut routing_table = RoutingTable::new(
+            Arc::new(NodeData {
+                addr: "127.0.0.1:8080".to_string(),
+                id: Key::rand_with(&mut rng),
+            }),
+            Arc::new(config),
+            Arc::new(VirtualClock::new()),
+        );
+        for port in 0..300 {
+            routing_table.update_node(NodeData {
+                addr: format!("127.0.0.1:{}", port),
+                id: Key::rand_with(&mut rng),
+            });
+        }
+
+        // counts that end in the middle of a routing bucket and that exceed the number of nodes
+        for &count in &[1, 3, 10, 50, 500] {
+            for _ in 0..20 {
+                let key = Key::rand_with(&mut rng);
+                let mut nodes = routing_table.nodes();
+                nodes.sort_by_key(|node| node.id.xor(&key));
+                nodes.truncate(count);
+                assert_eq!(routing_table.get_closest_nodes(&key, count), nodes);
             }
         }
-        ret
     }
 
-    /// Returns the number of routing buckets in the routing table.
-    pub fn size(&self) -> usize {
-        self.buckets.len()
+    #[test]
+    fn test_restart_from_snapshot() {
+        let (mut sim, _, nodes) = Simulation::with_nodes(6, 10, &NodeConfig::default());
+        let key = Key::new([4; 32]);
+        sim.insert(nodes[0], key, b"value");
+
+        let path =
+            std::env::temp_dir().join(format!("kademlia-dht-{}.snapshot", std::process::id()));
+        sim.snapshot(nodes[3]).save(&path).unwrap();
+        let snapshot = RoutingSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let departed = sim.node_data(nodes[4]);
+        assert!(snapshot.nodes.contains(&departed));
+        sim.remove_node(nodes[3]);
+        sim.remove_node(nodes[4]);
+
+        let restarted = sim.add_node_from_snapshot(snapshot.clone(), NodeConfig::default());
+        assert_eq!(sim.node_data(restarted).id, snapshot.id);
+        let nodes_after_restart = sim.snapshot(restarted).nodes;
+        assert!(!nodes_after_restart.is_empty());
+        assert!(!nodes_after_restart.contains(&departed));
+        assert_eq!(sim.get(restarted, key), Some(b"value".to_vec()));
     }
 }
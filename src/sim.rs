@@ -0,0 +1,618 @@
+//! A deterministic network simulator for testing nodes with virtual time.
+//!
+//! A `Simulation` runs any number of nodes on a single thread. Messages are delivered through an
+//! in-memory network with configurable latency, packet loss, and partitions, and all time is read
+//! from a `VirtualClock` that only advances when the simulation processes its next event. All
+//! randomness, including node ids, comes from an RNG seeded by the simulation's seed, so a scenario
+//! that is run with the same seed always produces the same result.
+//!
+//! # Examples
+//!
+//! ```
+//! use kademlia_dht::sim::Simulation;
+//! use kademlia_dht::{Key, NodeConfig};
+//! use std::time::Duration;
+//!
+//! let mut sim = Simulation::new(7);
+//! sim.set_latency(Duration::from_millis(10), Duration::from_millis(50));
+//!
+//! let bootstrap = sim.add_node(None, NodeConfig::default());
+//! let nodes: Vec<_> = (0..10)
+//!     .map(|_| sim.add_node(Some(bootstrap), NodeConfig::default()))
+//!     .collect();
+//!
+//! let key = Key::new([1; 32]);
+//! sim.insert(nodes[0], key, b"value");
+//! assert_eq!(sim.get(nodes[9], key), Some(b"value".to_vec()));
+//! ```
+
+use crate::clock::Clock;
+use crate::config::NodeConfig;
+use crate::key::Key;
+use crate::node::core::{Event, NodeCore, OperationId};
+use crate::node::node_data::NodeData;
+use crate::protocol::{check_value_size, decode_message, encode_message, max_message_length};
+use crate::routing::RoutingSnapshot;
+use crate::storage::MemoryStorage;
+use log::warn;
+use rand::{Rng, SeedableRng, StdRng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::SteadyTime;
+
+/// A `Clock` whose time only advances when the simulation processes its next event.
+pub struct VirtualClock {
+    start: SteadyTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+impl VirtualClock {
+    /// Constructs a new `VirtualClock` with no elapsed time.
+    pub fn new() -> Self {
+        VirtualClock {
+            start: SteadyTime::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Returns the amount of virtual time that has elapsed.
+    pub fn elapsed(&self) -> Duration {
+        *self.lock()
+    }
+
+    /// Converts a time returned by `now` into the elapsed time since the clock was constructed.
+    fn elapsed_at(&self, time: SteadyTime) -> Duration {
+        (time - self.start)
+            .to_std()
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    }
+
+    pub(crate) fn set_elapsed(&self, elapsed: Duration) {
+        let mut curr_elapsed = self.lock();
+        if elapsed > *curr_elapsed {
+            *curr_elapsed = elapsed;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Duration> {
+        match self.elapsed.lock() {
+            Ok(elapsed) => elapsed,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SteadyTime {
+        let elapsed = self.elapsed();
+        self.start + time::Duration::microseconds(elapsed.as_micros() as i64)
+    }
+}
+
+/// An identifier for a node in a `Simulation`.
+pub type SimNodeId = usize;
+
+/// Counters describing the messages sent during a simulation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    /// The number of messages sent by all nodes.
+    pub messages_sent: u64,
+    /// The number of messages delivered to a node.
+    pub messages_delivered: u64,
+    /// The number of messages dropped because of packet loss, a partition, or a removed node.
+    pub messages_dropped: u64,
+}
+
+enum SimEvent {
+    Deliver { dest: SimNodeId, bytes: Vec<u8> },
+    Wake(SimNodeId),
+}
+
+struct SimNode {
+    core: NodeCore,
+    config: Arc<NodeConfig>,
+    next_wake: Option<Duration>,
+}
+
+/// A deterministic, single-threaded simulation of a network of nodes.
+pub struct Simulation {
+    clock: Arc<VirtualClock>,
+    rng: StdRng,
+    nodes: Vec<Option<SimNode>>,
+    addrs: HashMap<String, SimNodeId>,
+    queue: BTreeMap<(Duration, u64), SimEvent>,
+    next_sequence: u64,
+    min_latency: Duration,
+    max_latency: Duration,
+    loss_rate: f64,
+    partition: HashSet<SimNodeId>,
+    completed: HashMap<(SimNodeId, OperationId), Event>,
+    stats: SimStats,
+}
+
+impl Simulation {
+    /// Constructs a new, empty `Simulation` whose randomness is derived from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Simulation {
+            clock: Arc::new(VirtualClock::new()),
+            rng: StdRng::from_seed(&[seed as usize]),
+            nodes: Vec::new(),
+            addrs: HashMap::new(),
+            queue: BTreeMap::new(),
+            next_sequence: 0,
+            min_latency: Duration::from_millis(0),
+            max_latency: Duration::from_millis(0),
+            loss_rate: 0.0,
+            partition: HashSet::new(),
+            completed: HashMap::new(),
+            stats: SimStats::default(),
+        }
+    }
+
+    /// Sets the range of latencies of a message. Each message is delayed by a latency chosen
+    /// uniformly at random from `[min, max]`.
+    pub fn set_latency(&mut self, min: Duration, max: Duration) {
+        self.min_latency = min;
+        self.max_latency = max.max(min);
+    }
+
+    /// Sets the probability in `[0, 1]` that a message is dropped.
+    pub fn set_loss_rate(&mut self, loss_rate: f64) {
+        self.loss_rate = loss_rate;
+    }
+
+    /// Partitions the network so that messages between `group` and the remaining nodes are
+    /// dropped.
+    pub fn partition(&mut self, group: &[SimNodeId]) {
+        self.partition = group.iter().cloned().collect();
+    }
+
+    /// Removes any partition of the network.
+    pub fn heal(&mut self) {
+        self.partition.clear();
+    }
+
+    /// Returns the amount of virtual time that has elapsed.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Returns the counters describing the messages sent so far.
+    pub fn stats(&self) -> SimStats {
+        self.stats
+    }
+
+    /// Returns the `NodeData` of the node with `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the node does not exist or has been removed.
+    pub fn node_data(&self, id: SimNodeId) -> NodeData {
+        self.node(id).core.node_data().clone()
+    }
+
+    /// Adds a node to the simulation that bootstraps from the node with id `bootstrap`, and runs
+    /// the simulation until it finished bootstrapping. The node uses the id in `config` if there
+    /// is one, and a random id otherwise; `identity_file` is ignored.
+    pub fn add_node(&mut self, bootstrap: Option<SimNodeId>, config: NodeConfig) -> SimNodeId {
+        let node_id = Key::rand_with(&mut self.rng);
+        let id = self.spawn_node(config.id.unwrap_or(node_id), config);
+        let bootstrap = bootstrap.map(|bootstrap| self.node_data(bootstrap));
+        let operation_id = self.node_mut(id).core.bootstrap(bootstrap);
+        self.flush(id);
+        self.run_until_complete(id, operation_id);
+        id
+    }
+
+    /// Adds a node to the simulation with the id in `snapshot` that bootstraps from the contacts
+    /// in `snapshot`, and runs the simulation until it finished bootstrapping. The node has a new
+    /// address.
+    pub fn add_node_from_snapshot(
+        &mut self,
+        snapshot: RoutingSnapshot,
+        config: NodeConfig,
+    ) -> SimNodeId {
+        let id = self.spawn_node(snapshot.id, config);
+        let operation_id = self.node_mut(id).core.restore(snapshot.nodes);
+        self.flush(id);
+        self.run_until_complete(id, operation_id);
+        id
+    }
+
+    /// Constructs a simulation with a bootstrap node and `count` nodes that bootstrap from it, all
+    /// using `config`. Returns the simulation, the bootstrap node, and the remaining nodes.
+    #[cfg(test)]
+    pub(crate) fn with_nodes(
+        seed: u64,
+        count: usize,
+        config: &NodeConfig,
+    ) -> (Self, SimNodeId, Vec<SimNodeId>) {
+        let mut sim = Simulation::new(seed);
+        let bootstrap = sim.add_node(None, config.clone());
+        let nodes = (0..count)
+            .map(|_| sim.add_node(Some(bootstrap), config.clone()))
+            .collect();
+        (sim, bootstrap, nodes)
+    }
+
+    fn spawn_node(&mut self, node_id: Key, config: NodeConfig) -> SimNodeId {
+        let id = self.nodes.len();
+        let addr = SocketAddr::new(Ipv4Addr::from(0x0A00_0001 + id as u32).into(), 8080);
+        let node_rng = StdRng::from_seed(&[self.rng.gen::<usize>(), self.rng.gen::<usize>()]);
+        let config = Arc::new(config);
+        let clock = Arc::clone(&self.clock) as Arc<dyn Clock>;
+        let storage = MemoryStorage::with_clock(Arc::clone(&clock));
+        let core = NodeCore::new(
+            addr.to_string(),
+            node_id,
+            Arc::clone(&config),
+            clock,
+            node_rng,
+            Box::new(storage),
+        );
+        self.addrs.insert(addr.to_string(), id);
+        self.nodes.push(Some(SimNode {
+            core,
+            config,
+            next_wake: None,
+        }));
+        id
+    }
+
+    /// Returns `true` if the node with `id` has bootstrapped.
+    ///
+    /// Panics if the node does not exist or has been removed.
+    pub fn is_bootstrapped(&self, id: SimNodeId) -> bool {
+        self.node(id).core.is_bootstrapped()
+    }
+
+    /// Returns a snapshot of the id and routing table of the node with `id`.
+    ///
+    /// Panics if the node does not exist or has been removed.
+    pub fn snapshot(&mut self, id: SimNodeId) -> RoutingSnapshot {
+        let operation_id = self.node_mut(id).core.snapshot();
+        self.flush(id);
+        match self.run_until_complete(id, operation_id) {
+            Some(Event::Snapshot(_, snapshot)) => snapshot,
+            _ => panic!("node {} did not take a snapshot", id),
+        }
+    }
+
+    /// Removes the node with `id` from the simulation. Messages sent to it are dropped.
+    pub fn remove_node(&mut self, id: SimNodeId) {
+        self.nodes[id] = None;
+    }
+
+    /// Inserts a key-value pair into the DHT from the node with `id`, and runs the simulation until
+    /// the `STORE` RPCs complete. Returns the nodes that acknowledged the `STORE` RPC.
+    pub fn insert(&mut self, id: SimNodeId, key: Key, value: &[u8]) -> Vec<NodeData> {
+        self.insert_item(id, key, value, None, 0)
+    }
+
+    /// Inserts a key-value pair into the DHT from the node with `id` like `insert`, but the node
+    /// only republishes the pair if at least `min_replicas` nodes acknowledge the `STORE` RPC.
+    pub fn insert_with_min_replicas(
+        &mut self,
+        id: SimNodeId,
+        key: Key,
+        value: &[u8],
+        min_replicas: usize,
+    ) -> Vec<NodeData> {
+        self.insert_item(id, key, value, None, min_replicas)
+    }
+
+    /// Inserts a key-value pair that expires after `ttl` into the DHT from the node with `id`, and
+    /// runs the simulation until the `STORE` RPCs complete. Returns the nodes that acknowledged
+    /// the `STORE` RPC.
+    pub fn insert_with_ttl(
+        &mut self,
+        id: SimNodeId,
+        key: Key,
+        value: &[u8],
+        ttl: Duration,
+    ) -> Vec<NodeData> {
+        self.insert_item(id, key, value, Some(ttl), 0)
+    }
+
+    fn insert_item(
+        &mut self,
+        id: SimNodeId,
+        key: Key,
+        value: &[u8],
+        ttl: Option<Duration>,
+        min_replicas: usize,
+    ) -> Vec<NodeData> {
+        let operation_id = self
+            .node_mut(id)
+            .core
+            .insert(key, value.to_vec(), ttl, min_replicas);
+        self.flush(id);
+        match self.run_until_complete(id, operation_id) {
+            Some(Event::Inserted(_, confirmed)) => confirmed,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Stops republishing `key` from the node with `id`. Returns `false` if the node was not
+    /// republishing the key.
+    pub fn stop_republishing(&mut self, id: SimNodeId, key: Key) -> bool {
+        self.node_mut(id).core.stop_republishing(&key)
+    }
+
+    /// Gets the value associated with `key` from the node with `id`, and runs the simulation until
+    /// the lookup finishes.
+    pub fn get(&mut self, id: SimNodeId, key: Key) -> Option<Vec<u8>> {
+        let operation_id = self.node_mut(id).core.get(key);
+        self.flush(id);
+        match self.run_until_complete(id, operation_id) {
+            Some(Event::FoundValue(_, value)) => value,
+            _ => None,
+        }
+    }
+
+    /// Looks up the closest nodes to `key` from the node with `id`, and runs the simulation until
+    /// the lookup finishes.
+    pub fn find_node(&mut self, id: SimNodeId, key: Key) -> Vec<NodeData> {
+        let operation_id = self.node_mut(id).core.find_node(key);
+        self.flush(id);
+        match self.run_until_complete(id, operation_id) {
+            Some(Event::FoundNodes(_, nodes)) => nodes,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Runs the simulation for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.clock.elapsed() + duration;
+        while let Some((&(time, _), _)) = self.queue.iter().next() {
+            if time > end {
+                break;
+            }
+            self.step();
+        }
+        self.clock.set_elapsed(end);
+    }
+
+    /// Runs the simulation until the operation with `operation_id` on the node with `id` completes
+    /// or until there are no more events.
+    fn run_until_complete(&mut self, id: SimNodeId, operation_id: OperationId) -> Option<Event> {
+        loop {
+            if let Some(event) = self.completed.remove(&(id, operation_id)) {
+                return Some(event);
+            }
+            if self.nodes[id].is_none() || !self.step() {
+                return None;
+            }
+        }
+    }
+
+    /// Processes the next event. Returns `false` if there are no more events.
+    fn step(&mut self) -> bool {
+        let key = match self.queue.keys().next() {
+            Some(key) => *key,
+            None => return false,
+        };
+        let event = match self.queue.remove(&key) {
+            Some(event) => event,
+            None => return false,
+        };
+        self.clock.set_elapsed(key.0);
+
+        match event {
+            SimEvent::Deliver { dest, bytes } => {
+                if let Some(node) = self.nodes[dest].as_mut() {
+                    self.stats.messages_delivered += 1;
+                    // messages are delivered whole, so fragmentation is not simulated
+                    let message = decode_message(&bytes, max_message_length(&node.config))
+                        .and_then(|message| {
+                            check_value_size(&message, node.config.max_value_size)?;
+                            Ok(message)
+                        });
+                    match message {
+                        Ok(message) => node.core.handle_message(message),
+                        Err(err) => warn!("Simulation: Dropped message: {}", err),
+                    }
+                    self.flush(dest);
+                } else {
+                    self.stats.messages_dropped += 1;
+                }
+            }
+            SimEvent::Wake(id) => {
+                let is_current = match self.nodes[id].as_mut() {
+                    Some(node) if node.next_wake == Some(key.0) => {
+                        node.next_wake = None;
+                        node.core.handle_timeouts();
+                        true
+                    }
+                    _ => false,
+                };
+                if is_current {
+                    self.flush(id);
+                }
+            }
+        }
+        true
+    }
+
+    /// Sends the outgoing messages of the node with `id`, collects its completed operations, and
+    /// schedules its next timeout.
+    fn flush(&mut self, id: SimNodeId) {
+        let mut transmits = Vec::new();
+        let next_deadline = match self.nodes[id].as_mut() {
+            Some(node) => {
+                while let Some(transmit) = node.core.poll_transmit() {
+                    transmits.push(transmit);
+                }
+                while let Some(event) = node.core.poll_event() {
+                    let operation_id = match event {
+                        Event::Bootstrapped(operation_id)
+                        | Event::FoundNodes(operation_id, _)
+                        | Event::FoundValue(operation_id, _)
+                        | Event::Inserted(operation_id, _)
+                        | Event::Snapshot(operation_id, _) => operation_id,
+                    };
+                    self.completed.insert((id, operation_id), event);
+                }
+                self.clock.elapsed_at(node.core.next_deadline())
+            }
+            None => return,
+        };
+
+        let message_length = max_message_length(&self.node(id).config);
+        for (message, dest) in transmits {
+            self.stats.messages_sent += 1;
+            let bytes = match encode_message(&message, message_length) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    warn!("Simulation: Could not encode message: {}", err);
+                    continue;
+                }
+            };
+            let dest_id = match self.addrs.get(&dest.addr) {
+                Some(dest_id) => *dest_id,
+                None => {
+                    self.stats.messages_dropped += 1;
+                    continue;
+                }
+            };
+            let is_partitioned = self.partition.contains(&id) != self.partition.contains(&dest_id);
+            if is_partitioned || self.rng.gen::<f64>() < self.loss_rate {
+                self.stats.messages_dropped += 1;
+                continue;
+            }
+            let time = self.clock.elapsed() + self.latency();
+            self.schedule(
+                time,
+                SimEvent::Deliver {
+                    dest: dest_id,
+                    bytes,
+                },
+            );
+        }
+
+        let node = self.node_mut(id);
+        let should_schedule = match node.next_wake {
+            Some(next_wake) => next_deadline < next_wake,
+            None => true,
+        };
+        if should_schedule {
+            node.next_wake = Some(next_deadline);
+            self.schedule(next_deadline, SimEvent::Wake(id));
+        }
+    }
+
+    fn latency(&mut self) -> Duration {
+        let min = self.min_latency.as_micros() as u64;
+        let max = self.max_latency.as_micros() as u64;
+        Duration::from_micros(self.rng.gen_range(min, max + 1))
+    }
+
+    fn schedule(&mut self, time: Duration, event: SimEvent) {
+        self.queue.insert((time, self.next_sequence), event);
+        self.next_sequence += 1;
+    }
+
+    fn node(&self, id: SimNodeId) -> &SimNode {
+        self.nodes[id].as_ref().expect("node has been removed")
+    }
+
+    fn node_mut(&mut self, id: SimNodeId) -> &mut SimNode {
+        self.nodes[id].as_mut().expect("node has been removed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimStats, Simulation};
+    use crate::config::NodeConfig;
+    use crate::key::Key;
+    use crate::node::node_data::NodeData;
+    use std::time::Duration;
+
+    /// Runs a scenario with churn, packet loss, and a partition, and returns everything observable
+    /// about its outcome.
+    fn run_scenario(seed: u64) -> (Vec<Option<Vec<u8>>>, Vec<NodeData>, SimStats, Duration) {
+        let config = NodeConfig::builder()
+            .replication_param(8)
+            .request_timeout(Duration::from_millis(500))
+            .build();
+        let mut sim = Simulation::new(seed);
+        sim.set_latency(Duration::from_millis(5), Duration::from_millis(80));
+        sim.set_loss_rate(0.05);
+
+        let bootstrap = sim.add_node(None, config.clone());
+        let nodes: Vec<_> = (0..40)
+            .map(|_| sim.add_node(Some(bootstrap), config.clone()))
+            .collect();
+
+        let keys: Vec<Key> = (0..10u8).map(|i| Key::new([i; 32])).collect();
+        for (i, key) in keys.iter().enumerate() {
+            sim.insert(nodes[i], *key, format!("value-{}", i).as_bytes());
+        }
+
+        for node in nodes[10..20].iter().step_by(3) {
+            sim.remove_node(*node);
+        }
+        sim.partition(&nodes[30..35]);
+        sim.run_for(Duration::from_secs(10));
+
+        let mut values = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            values.push(sim.get(nodes[20 + i % 10], *key));
+        }
+        sim.heal();
+        let closest = sim.find_node(nodes[31], keys[0]);
+        (values, closest, sim.stats(), sim.elapsed())
+    }
+
+    #[test]
+    fn test_insert_get() {
+        let mut sim = Simulation::new(0);
+        sim.set_latency(Duration::from_millis(1), Duration::from_millis(20));
+        let bootstrap = sim.add_node(None, NodeConfig::default());
+        let nodes: Vec<_> = (0..30)
+            .map(|_| sim.add_node(Some(bootstrap), NodeConfig::default()))
+            .collect();
+
+        for (i, node) in nodes.iter().enumerate() {
+            let confirmed = sim.insert(*node, Key::new([i as u8; 32]), &[i as u8]);
+            assert_eq!(confirmed.len(), NodeConfig::default().replication_param);
+        }
+        for (i, node) in nodes.iter().rev().enumerate() {
+            assert_eq!(sim.get(*node, Key::new([i as u8; 32])), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn test_same_seed_is_deterministic() {
+        let first = run_scenario(42);
+        let second = run_scenario(42);
+        assert_eq!(first, second);
+        assert!(first.0.iter().any(|value| value.is_some()));
+    }
+
+    #[test]
+    fn test_partition_drops_messages() {
+        let mut sim = Simulation::new(1);
+        let bootstrap = sim.add_node(None, NodeConfig::default());
+        let node = sim.add_node(Some(bootstrap), NodeConfig::default());
+        let key = Key::new([3; 32]);
+        sim.insert(bootstrap, key, b"value");
+
+        sim.partition(&[node]);
+        let dropped = sim.stats().messages_dropped;
+        assert_eq!(sim.get(node, key), None);
+        assert!(sim.stats().messages_dropped > dropped);
+
+        sim.heal();
+        assert_eq!(sim.get(bootstrap, key), Some(b"value".to_vec()));
+    }
+}
@@ -0,0 +1,462 @@
+use crate::error::Result;
+use crate::key::Key;
+use crate::storage::{StorageBackend, StorageEntry, StorageStats};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The minimum number of records in the log before it is compacted.
+const COMPACTION_MIN_RECORDS: usize = 1024;
+
+/// The number of bytes in the header of a record: the length and the checksum of its payload.
+const RECORD_HEADER_LENGTH: usize = 8;
+
+/// An entry in the log. Times are in milliseconds since the Unix epoch so that they survive a
+/// restart.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Insert {
+        key: Key,
+        value: Vec<u8>,
+        stored_at: u64,
+        expires_at: u64,
+    },
+    Remove(Key),
+}
+
+struct Item {
+    value: Vec<u8>,
+    stored_at: u64,
+    expires_at: u64,
+}
+
+/// A `StorageBackend` that persists key-value pairs to an append-only log file.
+///
+/// Every insert and removal is appended to the log as a checksummed record and synced to disk
+/// before it is applied to the pairs, which are also kept in memory. If the record could not be
+/// written, the error is returned and the pairs are left unchanged. Expiration times are stored as
+/// absolute wall-clock times, so pairs that expired while the node was down are dropped when the
+/// log is reopened. Since each record is synced before the next one is appended, only the final
+/// record can be torn by a crash in the middle of a write, so the log is truncated at the first
+/// torn or corrupt record on open. Once the log holds more than twice as many records as live pairs, it is compacted by
+/// rewriting the live pairs to a new file that replaces the old one.
+///
+/// This type is only available with the `file-storage` feature.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    items: HashMap<Key, Item>,
+    expiration_times: BTreeMap<u64, HashSet<Key>>,
+    bytes: usize,
+    records: usize,
+}
+
+impl FileStorage {
+    /// Opens the log at `path`, creating it if it does not exist, and loads the pairs that have
+    /// not expired.
+    ///
+    /// Returns an error if the log could not be opened, read, or truncated.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = open_log(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut storage = FileStorage {
+            path,
+            file,
+            items: HashMap::new(),
+            expiration_times: BTreeMap::new(),
+            bytes: 0,
+            records: 0,
+        };
+        let valid_length = storage.replay(&bytes);
+        if valid_length < bytes.len() {
+            warn!(
+                "Truncated {} bytes of torn or corrupt records from {}",
+                bytes.len() - valid_length,
+                storage.path.display(),
+            );
+            storage.file.set_len(valid_length as u64)?;
+        }
+        storage.expire();
+        Ok(storage)
+    }
+
+    /// Applies the records in `bytes` to the in-memory pairs up to the first torn or corrupt
+    /// record, and returns the length of the prefix of `bytes` that ends with the last valid
+    /// record.
+    fn replay(&mut self, bytes: &[u8]) -> usize {
+        let mut offset = 0;
+        while let Some((record, length)) = decode_record(&bytes[offset..]) {
+            match record {
+                Record::Insert {
+                    key,
+                    value,
+                    stored_at,
+                    expires_at,
+                } => self.insert_item(
+                    key,
+                    Item {
+                        value,
+                        stored_at,
+                        expires_at,
+                    },
+                ),
+                Record::Remove(key) => {
+                    self.remove_item(&key);
+                }
+            }
+            self.records += 1;
+            offset += length;
+        }
+        offset
+    }
+
+    /// Rewrites the log so that it only contains the pairs that have not expired.
+    ///
+    /// Returns an error if the new log could not be written. The old log is left untouched in that
+    /// case.
+    pub fn compact(&mut self) -> Result<()> {
+        self.expire();
+        let mut temp_path = OsString::from(self.path.as_os_str());
+        temp_path.push(".compact");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut keys: Vec<_> = self.items.keys().cloned().collect();
+        keys.sort();
+        let mut bytes = Vec::new();
+        for key in keys {
+            let item = &self.items[&key];
+            bytes.extend(encode_record(&Record::Insert {
+                key,
+                value: item.value.clone(),
+                stored_at: item.stored_at,
+                expires_at: item.expires_at,
+            })?);
+        }
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&bytes)?;
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        self.file = open_log(&self.path)?;
+        self.records = self.items.len();
+        info!("Compacted {}", self.path.display());
+        Ok(())
+    }
+
+    /// Appends `record` to the log and syncs it to disk.
+    ///
+    /// Returns an error if the record could not be written or synced.
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let bytes = encode_record(record)?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn compact_if_needed(&mut self) {
+        if self.records >= COMPACTION_MIN_RECORDS && self.records > 2 * self.items.len() {
+            if let Err(err) = self.compact() {
+                warn!("Could not compact {}: {}", self.path.display(), err);
+            }
+        }
+    }
+
+    fn insert_item(&mut self, key: Key, item: Item) {
+        self.remove_item(&key);
+        self.bytes += item.value.len();
+        self.expiration_times
+            .entry(item.expires_at)
+            .or_default()
+            .insert(key);
+        self.items.insert(key, item);
+    }
+
+    fn remove_item(&mut self, key: &Key) -> Option<Item> {
+        let item = self.items.remove(key)?;
+        if let Some(keys) = self.expiration_times.get_mut(&item.expires_at) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.expiration_times.remove(&item.expires_at);
+            }
+        }
+        self.bytes -= item.value.len();
+        Some(item)
+    }
+}
+
+impl StorageBackend for FileStorage {
+    fn insert(&mut self, key: Key, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.expire();
+        let stored_at = unix_millis(SystemTime::now());
+        let expires_at = stored_at.saturating_add(ttl.as_millis() as u64);
+        let record = Record::Insert {
+            key,
+            value: value.clone(),
+            stored_at,
+            expires_at,
+        };
+        self.append(&record)?;
+        self.insert_item(
+            key,
+            Item {
+                value,
+                stored_at,
+                expires_at,
+            },
+        );
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn get(&mut self, key: &Key) -> Option<(Vec<u8>, Duration)> {
+        self.expire();
+        let curr_time = unix_millis(SystemTime::now());
+        self.items.get(key).map(|item| {
+            let remaining = Duration::from_millis(item.expires_at.saturating_sub(curr_time));
+            (item.value.clone(), remaining)
+        })
+    }
+
+    fn remove(&mut self, key: &Key) -> Result<Option<Vec<u8>>> {
+        self.expire();
+        if !self.items.contains_key(key) {
+            return Ok(None);
+        }
+        self.append(&Record::Remove(*key))?;
+        let item = self.remove_item(key);
+        self.compact_if_needed();
+        Ok(item.map(|item| item.value))
+    }
+
+    fn iter(&mut self) -> Box<dyn Iterator<Item = StorageEntry> + '_> {
+        self.expire();
+        let curr_time = unix_millis(SystemTime::now());
+        Box::new(self.items.iter().map(move |(key, item)| StorageEntry {
+            key: *key,
+            value: item.value.clone(),
+            age: Duration::from_millis(curr_time.saturating_sub(item.stored_at)),
+            remaining: Duration::from_millis(item.expires_at.saturating_sub(curr_time)),
+        }))
+    }
+
+    /// Removes the expired pairs from memory. Their records stay in the log until it is compacted,
+    /// and are skipped when the log is reopened.
+    fn expire(&mut self) -> usize {
+        let curr_time = unix_millis(SystemTime::now());
+        let mut expired_times_map = self.expiration_times.split_off(&(curr_time + 1));
+        mem::swap(&mut self.expiration_times, &mut expired_times_map);
+
+        let mut removed = 0;
+        for key in expired_times_map.into_values().flatten() {
+            info!("Removed {:?}", key);
+            if let Some(item) = self.items.remove(&key) {
+                self.bytes -= item.value.len();
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.compact_if_needed();
+        }
+        removed
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            entries: self.items.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The 32-bit FNV-1a hash of `bytes`, used to detect torn or corrupt records.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Encodes `record` as its length, its checksum, and its serialized payload.
+fn encode_record(record: &Record) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(record, bincode::Infinite)?;
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Decodes the record at the start of `bytes`, and returns it with its encoded length. Returns
+/// `None` if `bytes` does not start with a complete, valid record.
+fn decode_record(bytes: &[u8]) -> Option<(Record, usize)> {
+    if bytes.len() < RECORD_HEADER_LENGTH {
+        return None;
+    }
+    let mut header = [0; 4];
+    header.copy_from_slice(&bytes[..4]);
+    let length = u32::from_le_bytes(header) as usize;
+    header.copy_from_slice(&bytes[4..8]);
+    let expected_checksum = u32::from_le_bytes(header);
+
+    let payload = bytes.get(RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + length)?;
+    if checksum(payload) != expected_checksum {
+        return None;
+    }
+    let record = bincode::deserialize(payload).ok()?;
+    Some((record, RECORD_HEADER_LENGTH + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStorage, COMPACTION_MIN_RECORDS, RECORD_HEADER_LENGTH};
+    use crate::key::Key;
+    use crate::storage::StorageBackend;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kademlia-dht-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = log_path("reopen");
+        let (stored, expired, removed) = (Key::new([1; 32]), Key::new([2; 32]), Key::new([3; 32]));
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage
+                .insert(stored, b"stored".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+            storage
+                .insert(expired, b"expired".to_vec(), Duration::from_secs(0))
+                .unwrap();
+            storage
+                .insert(removed, b"removed".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+            assert_eq!(storage.remove(&removed).unwrap(), Some(b"removed".to_vec()));
+        }
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        let (value, remaining) = storage.get(&stored).unwrap();
+        assert_eq!(value, b"stored".to_vec());
+        assert!(remaining <= Duration::from_secs(3600));
+        assert_eq!(storage.get(&expired), None);
+        assert_eq!(storage.get(&removed), None);
+        assert_eq!(storage.stats().entries, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let path = log_path("torn-record");
+        let (first, second, third) = (Key::new([1; 32]), Key::new([2; 32]), Key::new([3; 32]));
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage
+                .insert(first, b"first".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+            storage
+                .insert(second, b"second".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+        }
+        let length = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(length - 3).unwrap();
+
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            assert!(storage.get(&first).is_some());
+            assert_eq!(storage.get(&second), None);
+            storage
+                .insert(third, b"third".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+        }
+
+        // records appended after the truncation are readable
+        let mut storage = FileStorage::open(&path).unwrap();
+        assert!(storage.get(&first).is_some());
+        assert!(storage.get(&third).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let path = log_path("compaction");
+        let key = Key::new([1; 32]);
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            for i in 0..2 * COMPACTION_MIN_RECORDS {
+                storage
+                    .insert(key, i.to_le_bytes().to_vec(), Duration::from_secs(3600))
+                    .unwrap();
+            }
+            assert!(storage.records < COMPACTION_MIN_RECORDS);
+        }
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        let last = (2 * COMPACTION_MIN_RECORDS - 1).to_le_bytes().to_vec();
+        assert_eq!(storage.get(&key).map(|(value, _)| value), Some(last));
+        storage.compact().unwrap();
+        assert_eq!(storage.records, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_record_truncates_log() {
+        let path = log_path("corrupt-record");
+        let (first, second, third) = (Key::new([1; 32]), Key::new([2; 32]), Key::new([3; 32]));
+        let first_length;
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage
+                .insert(first, b"first".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+            first_length = fs::metadata(&path).unwrap().len() as usize;
+            storage
+                .insert(second, b"second".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+            // the records after the corrupt one, including this removal, are discarded with it
+            assert_eq!(storage.remove(&first).unwrap(), Some(b"first".to_vec()));
+            storage
+                .insert(third, b"third".to_vec(), Duration::from_secs(3600))
+                .unwrap();
+        }
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[first_length + RECORD_HEADER_LENGTH + 1] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        assert!(storage.get(&first).is_some());
+        assert_eq!(storage.get(&second), None);
+        assert_eq!(storage.get(&third), None);
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, first_length);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::Result;
+use crate::key::Key;
+use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use time::SteadyTime;
+
+#[cfg(feature = "file-storage")]
+mod file;
+
+#[cfg(feature = "file-storage")]
+pub use self::file::FileStorage;
+
+/// A key-value pair returned by `StorageBackend::iter`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageEntry {
+    /// The key of the pair.
+    pub key: Key,
+    /// The value of the pair.
+    pub value: Vec<u8>,
+    /// The amount of time since the pair was last inserted.
+    pub age: Duration,
+    /// The amount of time until the pair expires.
+    pub remaining: Duration,
+}
+
+/// Counters describing the contents of a `StorageBackend`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// The number of stored key-value pairs.
+    pub entries: usize,
+    /// The total size of the stored values in bytes.
+    pub bytes: usize,
+}
+
+/// The storage of the key-value pairs held by a node.
+///
+/// The node decides how long each pair lives: the time-to-live passed to `insert` has already been
+/// capped by `max_ttl`. A backend only has to forget a pair once its time-to-live has elapsed, and
+/// must never return an expired pair from `get` or `iter`.
+///
+/// A backend returns an error from `insert` or `remove` if the change could not be made durable,
+/// and must then leave the pairs unchanged. A `STORE` RPC whose pair could not be inserted is
+/// rejected instead of acknowledged.
+pub trait StorageBackend: Send {
+    /// Inserts a key-value pair that expires after `ttl`, replacing any existing pair with the same
+    /// key.
+    fn insert(&mut self, key: Key, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Returns the value associated with `key` and its remaining lifetime. Returns `None` if such a
+    /// key does not exist or has expired.
+    fn get(&mut self, key: &Key) -> Option<(Vec<u8>, Duration)>;
+
+    /// Removes the pair with `key` and returns its value. Returns `None` if such a key does not
+    /// exist or has expired.
+    fn remove(&mut self, key: &Key) -> Result<Option<Vec<u8>>>;
+
+    /// Returns an iterator over the pairs that have not expired.
+    fn iter(&mut self) -> Box<dyn Iterator<Item = StorageEntry> + '_>;
+
+    /// Removes all pairs whose time-to-live has elapsed, and returns the number of removed pairs.
+    fn expire(&mut self) -> usize;
+
+    /// Returns a snapshot of the counters describing the stored pairs.
+    fn stats(&self) -> StorageStats;
+}
+
+struct Item {
+    value: Vec<u8>,
+    stored_at: SteadyTime,
+    expires_at: SteadyTime,
+}
+
+/// A simple in-memory `StorageBackend` that removes stale items.
+///
+/// Each item has its own expiration time, and all items whose expiration time has passed are
+/// removed before every operation. This is the storage used by a node unless another backend is
+/// given.
+pub struct MemoryStorage {
+    items: HashMap<Key, Item>,
+    expiration_times: BTreeMap<SteadyTime, HashSet<Key>>,
+    bytes: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+impl MemoryStorage {
+    /// Constructs a new, empty `MemoryStorage`.
+    pub fn new() -> Self {
+        MemoryStorage::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Constructs a new, empty `MemoryStorage` that reads the time from `clock`.
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        MemoryStorage {
+            items: HashMap::new(),
+            expiration_times: BTreeMap::new(),
+            bytes: 0,
+            clock,
+        }
+    }
+
+    fn remove_item(&mut self, key: &Key) -> Option<Item> {
+        let item = self.items.remove(key)?;
+        if let Some(keys) = self.expiration_times.get_mut(&item.expires_at) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.expiration_times.remove(&item.expires_at);
+            }
+        }
+        self.bytes -= item.value.len();
+        Some(item)
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn insert(&mut self, key: Key, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.expire();
+        let curr_time = self.clock.now();
+        let expires_at = curr_time + time::Duration::milliseconds(ttl.as_millis() as i64);
+
+        self.remove_item(&key);
+        self.bytes += value.len();
+        self.items.insert(
+            key,
+            Item {
+                value,
+                stored_at: curr_time,
+                expires_at,
+            },
+        );
+        self.expiration_times
+            .entry(expires_at)
+            .or_default()
+            .insert(key);
+        Ok(())
+    }
+
+    fn get(&mut self, key: &Key) -> Option<(Vec<u8>, Duration)> {
+        self.expire();
+        let curr_time = self.clock.now();
+        self.items
+            .get(key)
+            .map(|item| (item.value.clone(), to_std(item.expires_at - curr_time)))
+    }
+
+    fn remove(&mut self, key: &Key) -> Result<Option<Vec<u8>>> {
+        self.expire();
+        Ok(self.remove_item(key).map(|item| item.value))
+    }
+
+    fn iter(&mut self) -> Box<dyn Iterator<Item = StorageEntry> + '_> {
+        self.expire();
+        let curr_time = self.clock.now();
+        Box::new(self.items.iter().map(move |(key, item)| StorageEntry {
+            key: *key,
+            value: item.value.clone(),
+            age: to_std(curr_time - item.stored_at),
+            remaining: to_std(item.expires_at - curr_time),
+        }))
+    }
+
+    fn expire(&mut self) -> usize {
+        let mut expired_times_map = self.expiration_times.split_off(&self.clock.now());
+        mem::swap(&mut self.expiration_times, &mut expired_times_map);
+
+        let mut removed = 0;
+        for key in expired_times_map.into_values().flatten() {
+            info!("Removed {:?}", key);
+            if let Some(item) = self.items.remove(&key) {
+                self.bytes -= item.value.len();
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            entries: self.items.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+/// Converts a `time::Duration` into a `std::time::Duration`. Negative durations become zero.
+fn to_std(duration: time::Duration) -> Duration {
+    duration.to_std().unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStorage, StorageBackend, StorageStats};
+    use crate::key::Key;
+    use crate::sim::VirtualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_per_item_expiration() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut storage = MemoryStorage::with_clock(Arc::clone(&clock) as Arc<_>);
+        let (short, long) = (Key::new([1; 32]), Key::new([2; 32]));
+        storage
+            .insert(short, b"short".to_vec(), Duration::from_secs(10))
+            .unwrap();
+        storage
+            .insert(long, b"long".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            storage.stats(),
+            StorageStats {
+                entries: 2,
+                bytes: 9,
+            }
+        );
+
+        clock.set_elapsed(Duration::from_secs(30));
+        assert_eq!(storage.get(&short), None);
+        assert_eq!(
+            storage.get(&long),
+            Some((b"long".to_vec(), Duration::from_secs(30)))
+        );
+        let entries: Vec<_> = storage.iter().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].age, Duration::from_secs(30));
+
+        // reinserting a pair replaces its value and expiration time
+        storage
+            .insert(long, b"longer".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        clock.set_elapsed(Duration::from_secs(70));
+        assert_eq!(storage.expire(), 0);
+        assert_eq!(storage.remove(&long).unwrap(), Some(b"longer".to_vec()));
+        assert_eq!(storage.stats(), StorageStats::default());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// A datagram transport that sends and receives addressed, unreliable messages.
+///
+/// `Transport` is implemented for `UdpSocket`, and for `MemoryTransport` to run many nodes in one
+/// process without binding ports. Implementations must behave like UDP: datagrams may be dropped,
+/// and a datagram that is larger than the receive buffer is truncated to the buffer's length.
+pub trait Transport: Send + Sync {
+    /// Sends `buf` to `dest`, returning the number of bytes sent.
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> io::Result<usize>;
+
+    /// Blocks until a datagram is received, and returns the number of bytes written to `buf` and
+    /// the address of the sender.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Returns the address that this transport is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, dest)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Default)]
+struct MemoryNetworkInner {
+    endpoints: HashMap<SocketAddr, Sender<Datagram>>,
+    next_port: u16,
+}
+
+/// An in-process network that delivers datagrams between `MemoryTransport`s.
+///
+/// Each transport bound on the network is assigned a unique loopback address. Datagrams sent to an
+/// address without a bound transport are silently dropped.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<MemoryNetworkInner>>,
+}
+
+impl MemoryNetwork {
+    /// Constructs a new, empty `MemoryNetwork`.
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+
+    /// Binds a new `MemoryTransport` to a unique address on the network.
+    pub fn bind(&self) -> MemoryTransport {
+        let (tx, rx) = channel();
+        let mut inner = self.lock();
+        let addr = loop {
+            inner.next_port = inner.next_port.wrapping_add(1);
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), inner.next_port);
+            if inner.next_port != 0 && !inner.endpoints.contains_key(&addr) {
+                break addr;
+            }
+        };
+        inner.endpoints.insert(addr, tx);
+        MemoryTransport {
+            addr,
+            rx: Mutex::new(rx),
+            network: self.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryNetworkInner> {
+        match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// A `Transport` that sends datagrams over a `MemoryNetwork`.
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    rx: Mutex<Receiver<Datagram>>,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if let Some(tx) = self.network.lock().endpoints.get(&dest) {
+            // the receiver may have been dropped, which is indistinguishable from packet loss
+            let _ = tx.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let rx = match self.rx.lock() {
+            Ok(rx) => rx,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let (datagram, src_addr) = rx
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "network closed"))?;
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok((len, src_addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.lock().endpoints.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryNetwork, Transport};
+    use crate::config::NodeConfig;
+    use crate::key::Key;
+    use crate::node::Node;
+    use std::thread;
+
+    #[test]
+    fn test_memory_transport_send_recv() {
+        let network = MemoryNetwork::new();
+        let a = network.bind();
+        let b = network.bind();
+        assert_ne!(a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        a.send_to(b"hello", b.local_addr().unwrap()).unwrap();
+        let mut buffer = [0u8; 3];
+        let (len, src_addr) = b.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"hel");
+        assert_eq!(src_addr, a.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_memory_transport_unbound_dest() {
+        let network = MemoryNetwork::new();
+        let a = network.bind();
+        let addr = {
+            let b = network.bind();
+            b.local_addr().unwrap()
+        };
+        assert_eq!(a.send_to(b"hello", addr).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_nodes_survive_poisoned_network() {
+        let network = MemoryNetwork::new();
+        let poisoned = network.clone();
+        let result = thread::spawn(move || {
+            let _inner = poisoned.lock();
+            panic!("poisoning the network");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(network.inner.is_poisoned());
+
+        let bootstrap = Node::with_transport(network.bind(), None, NodeConfig::default()).unwrap();
+        let mut node = Node::with_transport(
+            network.bind(),
+            Some(bootstrap.node_data()),
+            NodeConfig::default(),
+        )
+        .unwrap();
+        let key = Key::new([1; 32]);
+        assert_eq!(node.insert(key, b"value").unwrap().len(), 2);
+        assert_eq!(node.get(&key).unwrap(), Some(b"value".to_vec()));
+    }
+}